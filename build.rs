@@ -0,0 +1,5 @@
+// `sqlx::migrate!` embeds the migrations at compile time, so new ones need a
+// rebuild.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
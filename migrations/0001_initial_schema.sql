@@ -0,0 +1,97 @@
+-- The schema from before migrations were tracked, when schema.sql was run on
+-- every start. Everything here is idempotent so that it also applies cleanly to
+-- databases that schema.sql already set up. Later migrations can be plain.
+
+CREATE TABLE IF NOT EXISTS users (
+    id integer PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    username text NOT NULL UNIQUE,
+    profile text,
+    permission_level integer NOT NULL DEFAULT 0,
+    password text NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    session_token BYTEA PRIMARY KEY,
+    user_id integer REFERENCES users (id) ON DELETE CASCADE
+);
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS last_seen_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS expires_at timestamptz NOT NULL DEFAULT now() + interval '14 days';
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS id integer GENERATED ALWAYS AS IDENTITY;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent text;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip_address text;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS refresh_token_hash text UNIQUE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email text UNIQUE;
+
+CREATE TABLE IF NOT EXISTS password_reset_tokens (
+    token_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    expires_at timestamptz NOT NULL
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret bytea;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_pending_secret bytea;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_last_step bigint;
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    code_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS login_challenges (
+    challenge_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS login_attempts (
+    kind text NOT NULL,
+    key text NOT NULL,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    last_failed_at timestamptz NOT NULL DEFAULT now(),
+    locked_until timestamptz,
+    PRIMARY KEY (kind, key)
+);
+
+CREATE TABLE IF NOT EXISTS access_tokens (
+    id integer GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    scopes text[] NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz
+);
+
+-- users.permission_level refers to a role's level. It has no foreign key, so
+-- that a level without a role only loses its permissions instead of breaking.
+CREATE TABLE IF NOT EXISTS roles (
+    level integer PRIMARY KEY,
+    name text NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role_level integer NOT NULL REFERENCES roles (level) ON DELETE CASCADE,
+    permission text NOT NULL,
+    PRIMARY KEY (role_level, permission)
+);
+
+INSERT INTO roles (level, name) VALUES
+    (0, 'user'),
+    (1, 'admin'),
+    (2, 'moderator'),
+    (3, 'owner')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO role_permissions (role_level, permission) VALUES
+    (1, 'admin.view'),
+    (1, 'roles.assign'),
+    (1, 'metrics.view'),
+    (2, 'admin.view'),
+    (3, 'admin.view'),
+    (3, 'roles.assign'),
+    (3, 'metrics.view')
+ON CONFLICT DO NOTHING;
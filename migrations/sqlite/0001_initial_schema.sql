@@ -0,0 +1,47 @@
+-- The tables behind SqliteStore. Times are stored as Unix timestamps.
+
+CREATE TABLE users (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    username text NOT NULL UNIQUE,
+    email text UNIQUE,
+    profile text,
+    permission_level integer NOT NULL DEFAULT 0,
+    password text NOT NULL,
+    totp_secret blob
+);
+
+CREATE TABLE sessions (
+    session_token blob PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at integer NOT NULL DEFAULT (unixepoch()),
+    last_seen_at integer NOT NULL DEFAULT (unixepoch()),
+    expires_at integer NOT NULL,
+    user_agent text,
+    ip_address text
+);
+
+CREATE TABLE roles (
+    level integer PRIMARY KEY,
+    name text NOT NULL UNIQUE
+);
+
+CREATE TABLE role_permissions (
+    role_level integer NOT NULL REFERENCES roles (level) ON DELETE CASCADE,
+    permission text NOT NULL,
+    PRIMARY KEY (role_level, permission)
+);
+
+INSERT INTO roles (level, name) VALUES
+    (0, 'user'),
+    (1, 'admin'),
+    (2, 'moderator'),
+    (3, 'owner');
+
+INSERT INTO role_permissions (role_level, permission) VALUES
+    (1, 'admin.view'),
+    (1, 'roles.assign'),
+    (1, 'metrics.view'),
+    (2, 'admin.view'),
+    (3, 'admin.view'),
+    (3, 'roles.assign'),
+    (3, 'metrics.view');
@@ -0,0 +1,62 @@
+-- Catches the SQLite schema up with Postgres: session ids and refresh tokens,
+-- password resets, two-factor authentication, login throttling and personal
+-- access tokens. Times are stored as Unix timestamps and scopes joined by commas.
+
+CREATE TABLE sessions_new (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    session_token blob NOT NULL UNIQUE,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at integer NOT NULL DEFAULT (unixepoch()),
+    last_seen_at integer NOT NULL DEFAULT (unixepoch()),
+    expires_at integer NOT NULL,
+    user_agent text,
+    ip_address text,
+    refresh_token_hash text UNIQUE
+);
+
+INSERT INTO sessions_new (session_token, user_id, created_at, last_seen_at, expires_at, user_agent, ip_address)
+    SELECT session_token, user_id, created_at, last_seen_at, expires_at, user_agent, ip_address
+    FROM sessions;
+
+DROP TABLE sessions;
+ALTER TABLE sessions_new RENAME TO sessions;
+
+ALTER TABLE users ADD COLUMN totp_pending_secret blob;
+ALTER TABLE users ADD COLUMN totp_last_step integer;
+
+CREATE TABLE password_reset_tokens (
+    token_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    expires_at integer NOT NULL
+);
+
+CREATE TABLE recovery_codes (
+    code_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE login_challenges (
+    challenge_hash text PRIMARY KEY,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    expires_at integer NOT NULL
+);
+
+CREATE TABLE login_attempts (
+    kind text NOT NULL,
+    key text NOT NULL,
+    failed_attempts integer NOT NULL DEFAULT 0,
+    last_failed_at integer NOT NULL DEFAULT (unixepoch()),
+    locked_until integer,
+    PRIMARY KEY (kind, key)
+);
+
+CREATE TABLE access_tokens (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name text NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    scopes text NOT NULL,
+    created_at integer NOT NULL DEFAULT (unixepoch()),
+    last_used_at integer
+);
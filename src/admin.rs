@@ -0,0 +1,122 @@
+//! Account management for the `admin` command line tool, so that users can be
+//! looked after without writing SQL against the database.
+
+use std::error::Error;
+
+use argon2::Params;
+
+use crate::{
+    auth::{self, update_password},
+    bootstrap::bootstrap_admin,
+    config::BootstrapAdmin,
+    errors::{NoUser, PasswordChangeError, SignupError},
+    password::PasswordHashing,
+    repository::{Store, UserSummary},
+    roles::{ADMIN_ROLE, MODERATOR_ROLE, USER_ROLE},
+    validation::{normalize_email, MIN_PASSWORD_LENGTH},
+};
+
+pub type AdminResult<T> = Result<T, Box<dyn Error>>;
+
+async fn user_id(store: &Store, username: &str) -> AdminResult<i32> {
+    match store.user_id(username).await? {
+        Some(user_id) => Ok(user_id),
+        None => Err(NoUser(username.to_owned()).into()),
+    }
+}
+
+pub async fn user_exists(store: &Store, username: &str) -> AdminResult<bool> {
+    Ok(store.user_id(username).await?.is_some())
+}
+
+pub async fn list_users(store: &Store) -> AdminResult<Vec<UserSummary>> {
+    Ok(store.user_summaries().await?)
+}
+
+pub async fn create_user(
+    store: &Store,
+    argon2_params: &Params,
+    username: &str,
+    email: Option<&str>,
+    password: &str,
+) -> AdminResult<()> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(SignupError::InvalidPassword.into());
+    }
+
+    let email = email.and_then(normalize_email);
+    let hashing = PasswordHashing::new(argon2_params.clone());
+    auth::create_user(store, &hashing, username, email.as_deref(), password).await?;
+
+    Ok(())
+}
+
+/// Sets a new password and logs the user out everywhere, like a reset by email.
+pub async fn reset_password(
+    store: &Store,
+    argon2_params: &Params,
+    username: &str,
+    password: &str,
+) -> AdminResult<()> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(PasswordChangeError::InvalidPassword.into());
+    }
+
+    let user_id = user_id(store, username).await?;
+    let hashing = PasswordHashing::new(argon2_params.clone());
+    if !update_password(store, &hashing, user_id, password).await? {
+        return Err(PasswordChangeError::InvalidPassword.into());
+    }
+
+    store.delete_user_sessions(user_id, None).await?;
+
+    Ok(())
+}
+
+/// Returns false if the user was already an admin, or has a role that can't be
+/// promoted.
+pub async fn promote(store: &Store, username: &str) -> AdminResult<bool> {
+    user_id(store, username).await?;
+
+    Ok(store
+        .change_role(username, ADMIN_ROLE, &[USER_ROLE, MODERATOR_ROLE])
+        .await?)
+}
+
+/// Returns false if the user wasn't an admin.
+pub async fn demote(store: &Store, username: &str) -> AdminResult<bool> {
+    user_id(store, username).await?;
+
+    Ok(store.change_role(username, USER_ROLE, &[ADMIN_ROLE]).await?)
+}
+
+/// Logs a user out everywhere, returning how many sessions were revoked.
+pub async fn revoke_sessions(store: &Store, username: &str) -> AdminResult<u64> {
+    let user_id = user_id(store, username).await?;
+
+    Ok(store.delete_user_sessions(user_id, None).await?)
+}
+
+pub async fn delete_user(store: &Store, username: &str) -> AdminResult<()> {
+    let user_id = user_id(store, username).await?;
+    store.delete_user(user_id).await?;
+
+    Ok(())
+}
+
+/// Makes the first admin, the same way `BOOTSTRAP_ADMIN_USERNAME` does at
+/// startup.
+pub async fn bootstrap(
+    store: &Store,
+    argon2_params: &Params,
+    username: &str,
+    password: Option<String>,
+) -> AdminResult<()> {
+    let hashing = PasswordHashing::new(argon2_params.clone());
+    let admin = BootstrapAdmin {
+        username: username.to_owned(),
+        password,
+    };
+
+    Ok(bootstrap_admin(store, &hashing, &admin).await?)
+}
@@ -0,0 +1,226 @@
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    Extension, Form, Json,
+};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{new_session, verify_password_login, ClientInfo, SessionToken, VerifiedLogin},
+    errors::{AppError, TokenError, TwoFactorError},
+    password::PasswordHashing,
+    repository::{SessionIds, Store},
+    throttle::clear_failed_logins,
+    two_factor::verify_second_factor,
+    utils::random_hex,
+    Random, SESSION_LIFETIME_SECONDS,
+};
+
+/// Access tokens can't be taken back once issued, so they are kept short and
+/// clients use their refresh token to get new ones.
+const ACCESS_TOKEN_LIFETIME_SECONDS: i64 = 60 * 15;
+
+/// The contents of an access token. Each one is bound to the session that was
+/// created when the client logged in, so revoking or logging out that session
+/// stops its access tokens working too.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Claims {
+    /// The user's id.
+    sub: i32,
+    /// The session's id.
+    sid: i32,
+    iat: i64,
+    exp: i64,
+}
+
+/// Keys for signing and checking access tokens.
+#[derive(Clone)]
+pub(crate) struct TokenKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl TokenKeys {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    fn issue(&self, user_id: i32, session_id: i32) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let claims = Claims {
+            sub: user_id,
+            sid: session_id,
+            iat: now,
+            exp: now + ACCESS_TOKEN_LIFETIME_SECONDS,
+        };
+
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+    }
+
+    fn verify(&self, token: &str) -> Option<Claims> {
+        decode::<Claims>(token, &self.decoding, &Validation::new(Algorithm::HS256))
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+/// Returns the token from an `Authorization: Bearer` header.
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Finds the session an access token was issued for, if the token is valid.
+pub(crate) async fn access_token_session(
+    store: &Store,
+    keys: &TokenKeys,
+    token: &str,
+) -> Result<Option<SessionToken>, AppError> {
+    let Some(claims) = keys.verify(token) else {
+        return Ok(None);
+    };
+
+    store
+        .session_by_id(SessionIds {
+            session_id: claims.sid,
+            user_id: claims.sub,
+        })
+        .await
+}
+
+fn hash_refresh_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+/// Gives a session a new refresh token, replacing any it had before, and
+/// returns a fresh token pair for it.
+async fn issue_tokens(
+    store: &Store,
+    random: Random,
+    keys: &TokenKeys,
+    session_token: SessionToken,
+) -> Result<TokenResponse, AppError> {
+    let refresh_token = random_hex(random, 32);
+
+    let Some(ids) = store
+        .set_refresh_token(session_token, &hash_refresh_token(&refresh_token))
+        .await?
+    else {
+        return Err(TokenError::SessionEnded.into());
+    };
+
+    Ok(TokenResponse {
+        access_token: keys.issue(ids.user_id, ids.session_id)?,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_LIFETIME_SECONDS,
+        refresh_token,
+    })
+}
+
+/// Swaps a refresh token for a new token pair. Each refresh token works once,
+/// and only while its session is still valid.
+async fn refresh_tokens(
+    store: &Store,
+    random: Random,
+    keys: &TokenKeys,
+    refresh_token: &str,
+) -> Result<Option<TokenResponse>, AppError> {
+    let new_refresh_token = random_hex(random, 32);
+
+    let ids = store
+        .swap_refresh_token(
+            &hash_refresh_token(refresh_token),
+            &hash_refresh_token(&new_refresh_token),
+            SESSION_LIFETIME_SECONDS,
+        )
+        .await?;
+
+    let Some(ids) = ids else {
+        return Ok(None);
+    };
+
+    Ok(Some(TokenResponse {
+        access_token: keys.issue(ids.user_id, ids.session_id)?,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_LIFETIME_SECONDS,
+        refresh_token: new_refresh_token,
+    }))
+}
+
+/// Hands out API tokens, in the style of an OAuth 2.0 token endpoint. Clients
+/// either log in with `grant_type=password` (plus `code` when the account uses
+/// two-factor authentication) or renew with `grant_type=refresh_token`.
+pub(crate) async fn post_token(
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Extension(keys): Extension<TokenKeys>,
+    Form(request): Form<TokenRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = match request {
+        TokenRequest::Password {
+            username,
+            password,
+            code,
+        } => {
+            let VerifiedLogin {
+                user_id,
+                two_factor_enabled,
+            } = verify_password_login(&store, &hashing, &client_info, &username, &password).await?;
+
+            // The code is checked straight away rather than through a login
+            // challenge, so every wrong one counts towards the login throttle.
+            if two_factor_enabled {
+                let Some(code) = code else {
+                    return Err(TokenError::SecondFactorRequired.into());
+                };
+
+                if !verify_second_factor(&store, &client_info, user_id, &username, &code).await? {
+                    return Err(TwoFactorError::InvalidCode.into());
+                }
+            } else {
+                clear_failed_logins(&store, &username).await?;
+            }
+
+            let session_token = new_session(&store, random.clone(), user_id, &client_info).await?;
+            issue_tokens(&store, random, &keys, session_token).await?
+        }
+        TokenRequest::RefreshToken { refresh_token } => {
+            match refresh_tokens(&store, random, &keys, &refresh_token).await? {
+                Some(tokens) => tokens,
+                None => return Err(TokenError::InvalidRefreshToken.into()),
+            }
+        }
+    };
+
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(tokens)))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub(crate) enum TokenRequest {
+    Password {
+        username: String,
+        password: String,
+        code: Option<String>,
+    },
+    RefreshToken {
+        refresh_token: String,
+    },
+}
+
+#[derive(serde::Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+}
@@ -1,22 +1,31 @@
-use std::str::FromStr;
-
-use axum::http;
-use axum_login::tower_sessions::cookie;
-use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
 };
-use rand_core::{OsRng, RngCore};
-use sqlx::error::ErrorKind;
-use tracing::{info, error};
+
+use axum::{extract::ConnectInfo, http, response::IntoResponse};
+use rand_core::RngCore;
+use tracing::{error, info};
 
 use crate::{
-    errors::{LoginError, SignupError},
-    Database, Random, USER_COOKIE_NAME, users::PermissionLevel,
+    api::{access_token_session, bearer_token, TokenKeys},
+    config::CookieConfig,
+    csrf::csrf_token,
+    errors::{AppError, EmailError, LoginError, NotLoggedIn, PasswordChangeError, SignupError},
+    metrics,
+    password::PasswordHashing,
+    repository::{LoginDetails, Store},
+    roles::{Permission, Role},
+    throttle::{clear_failed_logins, login_lockout, record_failed_login},
+    tokens::{is_personal_access_token, personal_access_token_credential},
+    two_factor::create_login_challenge,
+    utils::{clear_legacy_session_cookie, get_cookie, logout_cookie, session_cookie},
+    validation::{valid_email, valid_username},
+    Random, LEGACY_SESSION_COOKIE_NAME, SESSION_COOKIE_NAME, SESSION_LIFETIME_SECONDS,
 };
 
-#[derive(Clone, Copy, Debug)]
-pub(crate) struct SessionToken(u128);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionToken(u128);
 
 impl FromStr for SessionToken {
     type Err = <u128 as FromStr>::Err;
@@ -40,198 +49,600 @@ impl SessionToken {
     pub fn into_database_value(self) -> Vec<u8> {
         self.0.to_le_bytes().to_vec()
     }
+
+    pub fn from_database_value(value: &[u8]) -> Option<Self> {
+        value.try_into().ok().map(|bytes| Self(u128::from_le_bytes(bytes)))
+    }
+}
+
+/// Details about the client making a request, recorded against its session.
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl ClientInfo {
+    fn from_request<B>(req: &http::Request<B>, trusted_proxies: &[IpAddr]) -> Self {
+        let user_agent = req
+            .headers()
+            .get(http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        let peer_address = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok());
+        let ip_address = client_address(peer_address, forwarded_for, trusted_proxies)
+            .map(|address| address.to_string());
+
+        Self { user_agent, ip_address }
+    }
+}
+
+/// Works out where a request came from. Each trusted proxy appends the address
+/// it got the request from to `X-Forwarded-For`, so the entries are followed
+/// from the right for as long as they were added by a trusted proxy. Anything
+/// further left was sent by the client and can't be believed.
+///
+/// The peer address is only missing if the server wasn't started with
+/// `into_make_service_with_connect_info`. Behind trusted proxies the last
+/// entry they added is used instead; otherwise the request has no address,
+/// which leaves it out of per-address throttling, so that is logged as an
+/// error.
+fn client_address(
+    peer_address: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut hops = forwarded_for.unwrap_or_default().rsplit(',').map(str::trim);
+
+    let mut address = match peer_address {
+        Some(address) => address,
+        None if !trusted_proxies.is_empty() => hops.next()?.parse().ok()?,
+        None => {
+            error!("The server doesn't provide client addresses, so they can't be throttled");
+            return None;
+        }
+    };
+
+    for hop in hops {
+        if !trusted_proxies.contains(&address) {
+            break;
+        }
+        match hop.parse() {
+            Ok(hop) => address = hop,
+            Err(_) => break,
+        }
+    }
+
+    Some(address)
 }
 
 #[derive(Clone)]
 pub(crate) struct User {
+    pub id: i32,
     pub username: String,
-    pub permission_level: PermissionLevel,
+    pub email: Option<String>,
+    pub role: Role,
+}
+
+/// What a personal access token is allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Scope {
+    ProfileRead,
+    ProfileWrite,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::ProfileRead, Scope::ProfileWrite, Scope::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ProfileRead => "profile:read",
+            Scope::ProfileWrite => "profile:write",
+            Scope::Admin => "admin",
+        }
+    }
 }
 
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// How a request proved who it is from.
 #[derive(Clone)]
-pub(crate) struct AuthState(Option<(SessionToken, Option<User>, Database)>);
+pub(crate) enum Credential {
+    /// A browser session, or an API access token issued for one. Sessions can
+    /// do everything their user can.
+    Session(SessionToken),
+    /// A personal access token, limited to its scopes.
+    AccessToken { user_id: i32, scopes: Vec<Scope> },
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthState(Option<(Credential, Option<User>, Store)>);
 
 impl AuthState {
     pub fn logged_in(&self) -> bool {
         self.0.is_some()
     }
 
-    pub async fn is_admin(&mut self) -> bool {
-        if let Some(user) = self.get_user().await {
-            user.permission_level == PermissionLevel::Admin
-        } else {
-            false
+    pub fn session_token(&self) -> Option<SessionToken> {
+        match self.0.as_ref()? {
+            (Credential::Session(session_token), _, _) => Some(*session_token),
+            (Credential::AccessToken { .. }, _, _) => None,
         }
     }
 
-    pub async fn get_user(&mut self) -> Option<&User> {
-        let (session_token, store, database) = self.0.as_mut()?;
-        if store.is_none() {
-            const QUERY: &str =
-                "SELECT id, username, permission_level FROM users JOIN sessions ON user_id = id WHERE session_token = $1;";
+    /// Whether the credential allows `scope`. Only personal access tokens are
+    /// limited, so this says nothing about whether anyone is logged in.
+    pub fn allows(&self, scope: Scope) -> bool {
+        match &self.0 {
+            Some((Credential::AccessToken { scopes, .. }, _, _)) => scopes.contains(&scope),
+            _ => true,
+        }
+    }
+
+    /// The token that forms must send back with state-changing requests.
+    pub fn csrf_token(&self) -> Option<String> {
+        self.session_token().map(csrf_token)
+    }
 
-            let user: Option<(i32, String, i32)> = sqlx::query_as(QUERY)
-                .bind(&session_token.into_database_value())
-                .fetch_optional(&*database)
-                .await
-                .unwrap();
+    /// Whether the user's role grants `permission`. Every permission is an
+    /// administrative one, so personal access tokens also need the admin scope.
+    pub async fn has_permission(&mut self, permission: Permission) -> Result<bool, AppError> {
+        if !self.allows(Scope::Admin) {
+            return Ok(false);
+        }
 
-            if let Some((_id, username, permission_level)) = user {
-                *store = Some(User { username, permission_level: PermissionLevel::from(permission_level) });
+        Ok(self
+            .get_user()
+            .await?
+            .is_some_and(|user| user.role.has_permission(permission)))
+    }
+
+    pub async fn get_user(&mut self) -> Result<Option<&User>, AppError> {
+        let Some((credential, user, store)) = self.0.as_mut() else {
+            return Ok(None);
+        };
+        if user.is_none() {
+            let user_id = match credential {
+                Credential::Session(session_token) => {
+                    let Some(user_id) = store.session_user(*session_token).await? else {
+                        return Ok(None);
+                    };
+                    user_id
+                }
+                Credential::AccessToken { user_id, .. } => *user_id,
+            };
+
+            if let Some(record) = store.user_by_id(user_id).await? {
+                *user = Some(User {
+                    id: record.id,
+                    username: record.username,
+                    email: record.email,
+                    role: Role::from_row(record.permission_level, record.role_name, record.permissions),
+                });
             }
         }
-        store.as_ref()
+        Ok(user.as_ref())
     }
-}
 
-pub(crate) async fn new_session(database: &Database, random: Random, user_id: i32) -> SessionToken {
-    const INSERT_TOKEN_QUERY: &str = "INSERT INTO sessions (session_token, user_id) VALUES ($1, $2);";
+    /// Like [`AuthState::get_user`], but fails when nobody is logged in.
+    pub async fn require_user(&mut self) -> Result<&User, AppError> {
+        match self.get_user().await? {
+            Some(user) => Ok(user),
+            None => Err(NotLoggedIn.into()),
+        }
+    }
+}
 
+pub(crate) async fn new_session(
+    store: &Store,
+    random: Random,
+    user_id: i32,
+    client_info: &ClientInfo,
+) -> Result<SessionToken, AppError> {
     let session_token = SessionToken::generate_new(random);
 
-    sqlx::query(INSERT_TOKEN_QUERY)
-        .bind(&session_token.into_database_value())
-        .bind(user_id)
-        .execute(database)
-        .await
-        .unwrap();
+    store
+        .create_session(session_token, user_id, SESSION_LIFETIME_SECONDS, client_info)
+        .await?;
 
-    session_token
+    Ok(session_token)
 }
 
 pub(crate) async fn auth<B>(
     mut req: http::Request<B>,
     next: axum::middleware::Next<B>,
-    database: Database,
+    store: Store,
+    cookies: CookieConfig,
+    keys: TokenKeys,
+    trusted_proxies: Vec<IpAddr>,
 ) -> axum::response::Response {
-    let session_token = req
-        .headers()
-        .get_all("Cookie")
-        .iter()
-        .filter_map(|cookie| {
-            cookie
-                .to_str()
-                .ok()
-                .and_then(|cookie| cookie.parse::<cookie::Cookie>().ok())
-        })
-        .find_map(|cookie| {
-            (cookie.name() == USER_COOKIE_NAME).then(move || cookie.value().to_owned())
-        })
+    let client_info = ClientInfo::from_request(&req, &trusted_proxies);
+
+    // API clients authenticate with a bearer token instead of cookies, and
+    // any cookies sent alongside one are ignored.
+    if let Some(token) = bearer_token(req.headers()) {
+        if is_personal_access_token(token) {
+            let credential = match personal_access_token_credential(&store, token).await {
+                Ok(credential) => credential,
+                Err(error) => return error.into_response(),
+            };
+            req.extensions_mut().insert(client_info);
+            req.extensions_mut()
+                .insert(AuthState(credential.map(|credential| (credential, None, store))));
+            return next.run(req).await;
+        }
+
+        let session_token = match access_token_session(&store, &keys, token).await {
+            Ok(session_token) => session_token,
+            Err(error) => return error.into_response(),
+        };
+        return authenticate(req, next, store, client_info, None, session_token).await;
+    }
+
+    let legacy_cookie = get_cookie(req.headers(), LEGACY_SESSION_COOKIE_NAME);
+    let session_token = get_cookie(req.headers(), &cookies.name(SESSION_COOKIE_NAME))
+        .or_else(|| legacy_cookie.clone())
         .and_then(|cookie_value| cookie_value.parse::<SessionToken>().ok());
 
+    // A session from a legacy cookie carries on under the new cookie, which
+    // is set along with the refresh, so the old one can be dropped right away.
+    let legacy_cookie_paths = legacy_cookie.map(|_| legacy_cookie_paths(req.uri().path()));
+
+    let mut response =
+        authenticate(req, next, store, client_info, Some(&cookies), session_token).await;
+
+    for path in legacy_cookie_paths.into_iter().flatten() {
+        if let Ok(value) = http::HeaderValue::from_str(&clear_legacy_session_cookie(&path)) {
+            response.headers_mut().append(http::header::SET_COOKIE, value);
+        }
+    }
+
+    response
+}
+
+/// The paths a legacy cookie seen on a request to `path` may be stored under:
+/// the site root, where most were set, and the request's own default path.
+fn legacy_cookie_paths(path: &str) -> Vec<String> {
+    let default_path = match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(index) => &path[..index],
+    };
+
+    if default_path == "/" {
+        vec!["/".to_owned()]
+    } else {
+        vec!["/".to_owned(), default_path.to_owned()]
+    }
+}
+
+/// Runs the request with the given session. Cookie-based sessions are kept up
+/// to date through `cookies`; bearer sessions pass `None` and get no cookies.
+async fn authenticate<B>(
+    mut req: http::Request<B>,
+    next: axum::middleware::Next<B>,
+    store: Store,
+    client_info: ClientInfo,
+    cookies: Option<&CookieConfig>,
+    session_token: Option<SessionToken>,
+) -> axum::response::Response {
+    req.extensions_mut().insert(client_info.clone());
+
+    let Some(session_token) = session_token else {
+        req.extensions_mut().insert(AuthState(None));
+        return next.run(req).await;
+    };
+
+    let refreshed = match store
+        .refresh_session(session_token, SESSION_LIFETIME_SECONDS, &client_info)
+        .await
+    {
+        Ok(refreshed) => refreshed,
+        Err(error) => return error.into_response(),
+    };
+
+    if !refreshed {
+        req.extensions_mut().insert(AuthState(None));
+        let mut response = next.run(req).await;
+        if let Some(cookies) = cookies {
+            set_cookie_unless_present(&mut response, cookies, logout_cookie(cookies));
+        }
+        return response;
+    }
+
     req.extensions_mut()
-        .insert(AuthState(session_token.map(|v| (v, None, database))));
+        .insert(AuthState(Some((Credential::Session(session_token), None, store))));
+
+    let mut response = next.run(req).await;
+    if let Some(cookies) = cookies {
+        set_cookie_unless_present(&mut response, cookies, session_cookie(cookies, session_token));
+    }
+    response
+}
 
-    next.run(req).await
+/// Adds a session cookie to the response, unless the handler already set one
+/// itself (for example when logging in or out).
+fn set_cookie_unless_present(
+    response: &mut axum::response::Response,
+    cookies: &CookieConfig,
+    cookie: String,
+) {
+    let already_set = response
+        .headers()
+        .get_all(http::header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.starts_with(&format!("{}=", cookies.name(SESSION_COOKIE_NAME))));
+
+    if !already_set {
+        if let Ok(value) = http::HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(http::header::SET_COOKIE, value);
+        }
+    }
 }
 
 pub(crate) async fn signup(
-    database: &Database,
+    store: &Store,
     random: Random,
+    hashing: &PasswordHashing,
+    client_info: &ClientInfo,
     username: &str,
+    email: Option<&str>,
     password: &str,
-) -> Result<SessionToken, SignupError> {
-    fn valid_username(username: &str) -> bool {
-        (1..20).contains(&username.len())
-            && username
-                .chars()
-                .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-'))
-    }
+) -> Result<SessionToken, AppError> {
+    let user_id = create_user(store, hashing, username, email, password).await?;
+
+    new_session(store, random, user_id, client_info).await
+}
 
+/// Checks and stores a new account, returning its id.
+pub(crate) async fn create_user(
+    store: &Store,
+    hashing: &PasswordHashing,
+    username: &str,
+    email: Option<&str>,
+    password: &str,
+) -> Result<i32, AppError> {
     if !valid_username(username) {
-        return Err(SignupError::InvalidUsername);
+        return Err(SignupError::InvalidUsername.into());
     }
 
-    const INSERT_USER_QUERY: &str =
-        "INSERT INTO users (username, password) VALUES ($1, $2) RETURNING id;";
+    if email.is_some_and(|email| !valid_email(email)) {
+        return Err(SignupError::InvalidEmail.into());
+    }
 
-    let hashed_password = match Pbkdf2.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng)) {
-        Ok(password) => password.to_string(),
-        Err(_) => return Err(SignupError::InvalidPassword),
+    let hashed_password = match hashing.hash(password).await {
+        Some(password) => password,
+        None => return Err(SignupError::InvalidPassword.into()),
     };
 
-    let fetch_one = sqlx::query_as(INSERT_USER_QUERY)
-        .bind(username)
-        .bind(hashed_password)
-        .fetch_one(database)
-        .await;
-
-    let user_id: i32 = match fetch_one {
-        Ok((user_id,)) => user_id,
-        Err(sqlx::Error::Database(database))
-            if database.kind() == ErrorKind::UniqueViolation =>
-        {
-            info!("Sign in error: Username already exists");
-            return Err(SignupError::UsernameExists);
-        }
-        Err(e) => {
-            error!("Internal Error: {}", e);
-            return Err(SignupError::InternalError);
-        }
+    store.create_user(username, email, &hashed_password).await
+}
+
+/// The result of a correct username and password.
+pub(crate) enum LoginOutcome {
+    Session(SessionToken),
+    /// The account has two-factor authentication enabled, so no session is
+    /// created until the challenge is completed with a code.
+    SecondFactorRequired(String),
+}
+
+/// A user whose password was right. That is only enough to log in when
+/// `two_factor_enabled` is false.
+pub(crate) struct VerifiedLogin {
+    pub user_id: i32,
+    pub two_factor_enabled: bool,
+}
+
+/// Checks a username and password, counting wrong ones towards the login
+/// throttle. Failures are left for the caller to clear once the login is
+/// complete.
+pub(crate) async fn verify_password_login(
+    store: &Store,
+    hashing: &PasswordHashing,
+    client_info: &ClientInfo,
+    username: &str,
+    password: &str,
+) -> Result<VerifiedLogin, AppError> {
+    let ip_address = client_info.ip_address.as_deref();
+    if let Some(retry_after) = login_lockout(store, username, ip_address).await? {
+        info!("Login for user '{}' throttled", username);
+        return Err(LoginError::TooManyAttempts(retry_after).into());
+    }
+
+    let Some(LoginDetails {
+        id: user_id,
+        password_hash: hashed_password,
+        two_factor_enabled,
+    }) = store.login_details(username).await?
+    else {
+        // Hash anyway so that response times don't reveal which usernames exist.
+        hashing.verify_dummy(password).await;
+        info!("User '{}' does not exist", username);
+        record_failed_login(store, username, ip_address).await?;
+        return Err(LoginError::InvalidCredentials.into());
     };
 
-    Ok(new_session(database, random, user_id).await)
+    let Some(verified) = hashing.verify(password, &hashed_password).await else {
+        info!("Password incorrect for user '{}'", username);
+        record_failed_login(store, username, ip_address).await?;
+        return Err(LoginError::InvalidCredentials.into());
+    };
+
+    info!("Password for user '{}' verified with {}", username, verified.algorithm);
+
+    if verified.needs_rehash && update_password(store, hashing, user_id, password).await? {
+        info!("Upgraded {} password hash for user '{}'", verified.algorithm, username);
+        metrics::record_password_rehashed(verified.algorithm);
+    }
+
+    Ok(VerifiedLogin {
+        user_id,
+        two_factor_enabled,
+    })
 }
 
 pub(crate) async fn login(
-    database: &Database,
+    store: &Store,
     random: Random,
+    hashing: &PasswordHashing,
+    client_info: &ClientInfo,
     username: String,
     password: String,
-) -> Result<SessionToken, LoginError> {
-    const LOGIN_QUERY: &str = "SELECT id, password FROM users WHERE users.username = $1;";
+) -> Result<LoginOutcome, AppError> {
+    let VerifiedLogin {
+        user_id,
+        two_factor_enabled,
+    } = verify_password_login(store, hashing, client_info, &username, &password).await?;
+
+    // Failures are only forgotten once the second factor is also right.
+    if two_factor_enabled {
+        let challenge = create_login_challenge(store, random, user_id).await?;
+        return Ok(LoginOutcome::SecondFactorRequired(challenge));
+    }
 
-    let row: Option<(i32, String)> = sqlx::query_as(LOGIN_QUERY)
-        .bind(&username)
-        .fetch_optional(database)
-        .await
-        .unwrap();
+    clear_failed_logins(store, &username).await?;
 
-    let (user_id, hashed_password) = if let Some(row) = row {
-        row
-    } else {
-        info!("User '{}' does not exist", username);
-        return Err(LoginError::UserDoesNotExist);
+    Ok(LoginOutcome::Session(
+        new_session(store, random, user_id, client_info).await?,
+    ))
+}
+
+/// Asks a logged in user for their password again before a sensitive change.
+/// This goes through the same checks as logging in, so wrong passwords count
+/// towards the lockout for the username and address, and a stolen session
+/// can't be used to guess the password. Returns false if it was wrong.
+pub(crate) async fn confirm_password(
+    store: &Store,
+    hashing: &PasswordHashing,
+    client_info: &ClientInfo,
+    username: &str,
+    password: &str,
+) -> Result<bool, AppError> {
+    match verify_password_login(store, hashing, client_info, username, password).await {
+        Ok(_) => {
+            clear_failed_logins(store, username).await?;
+            Ok(true)
+        }
+        Err(error) if matches!(error.downcast_ref(), Some(LoginError::InvalidCredentials)) => {
+            Ok(false)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Stores a new hash for the user's password, returning false if the password
+/// could not be hashed.
+pub(crate) async fn update_password(
+    store: &Store,
+    hashing: &PasswordHashing,
+    user_id: i32,
+    password: &str,
+) -> Result<bool, AppError> {
+    let Some(hashed_password) = hashing.hash(password).await else {
+        return Ok(false);
     };
 
-    let parsed_hash = PasswordHash::new(&hashed_password).unwrap();
-    if let Err(_err) = Pbkdf2.verify_password(password.as_bytes(), &parsed_hash) {
-        info!("Password incorrect for user '{}'", username);
-        return Err(LoginError::WrongPassword);
+    store.set_password_hash(user_id, &hashed_password).await?;
+
+    Ok(true)
+}
+
+pub(crate) async fn change_password(
+    mut auth_state: AuthState,
+    hashing: &PasswordHashing,
+    client_info: &ClientInfo,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), AppError> {
+    let user = auth_state.require_user().await?;
+    let (user_id, username) = (user.id, user.username.clone());
+    let session_token = auth_state.session_token();
+    let Some((_, _, store)) = auth_state.0 else {
+        return Err(NotLoggedIn.into());
+    };
+
+    if !confirm_password(&store, hashing, client_info, &username, current_password).await? {
+        info!("Password change rejected: current password incorrect");
+        return Err(PasswordChangeError::WrongPassword.into());
     }
 
-    Ok(new_session(database, random, user_id).await)
+    if !update_password(&store, hashing, user_id, new_password).await? {
+        return Err(PasswordChangeError::InvalidPassword.into());
+    }
+
+    store.delete_user_sessions(user_id, session_token).await?;
+
+    Ok(())
 }
 
-pub(crate) async fn delete_user(auth_state: AuthState) {
-    const DELETE_QUERY: &str = "DELETE FROM users 
-        WHERE users.id = (
-            SELECT user_id FROM sessions WHERE sessions.session_token = $1
-        );";
+pub(crate) async fn change_email(
+    mut auth_state: AuthState,
+    email: Option<&str>,
+) -> Result<(), AppError> {
+    if email.is_some_and(|email| !valid_email(email)) {
+        return Err(EmailError::InvalidEmail.into());
+    }
 
-    let auth_state = auth_state.0.unwrap();
-    sqlx::query(DELETE_QUERY)
-        .bind(&auth_state.0.into_database_value())
-        .execute(&auth_state.2)
-        .await
-        .unwrap();
+    let user_id = auth_state.require_user().await?.id;
+    let Some((_, _, store)) = auth_state.0 else {
+        return Err(NotLoggedIn.into());
+    };
+
+    store.set_email(user_id, email).await
 }
 
-pub(crate) async fn get_user(username: &str, database: &Database) -> Option<(String, Option<String>, i32)> {
-    const QUERY: &str =
-        "SELECT username, profile, permission_level FROM users WHERE username = $1;";
+/// Deletes the logged-in user's account, unless they are the last admin.
+pub(crate) async fn delete_user(mut auth_state: AuthState) -> Result<(), AppError> {
+    let user_id = auth_state.require_user().await?.id;
+    let Some((_, _, store)) = auth_state.0 else {
+        return Err(NotLoggedIn.into());
+    };
 
-    sqlx::query_as(QUERY)
-        .bind(username)
-        .fetch_optional(database)
-        .await
-        .unwrap()
+    store.delete_user(user_id).await
+}
+
+pub(crate) async fn delete_session(auth_state: AuthState) -> Result<(), AppError> {
+    if let Some((Credential::Session(session_token), _, store)) = auth_state.0 {
+        store.delete_session(session_token).await?;
+    }
+    Ok(())
 }
 
-pub(crate) async fn is_logged_in_user(auth_state: &mut AuthState, username: &str) -> bool {
-    auth_state
+pub(crate) async fn delete_all_sessions(mut auth_state: AuthState) -> Result<(), AppError> {
+    let Some(user_id) = auth_state.get_user().await?.map(|user| user.id) else {
+        return Ok(());
+    };
+
+    if let Some((_, _, store)) = auth_state.0 {
+        store.delete_user_sessions(user_id, None).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn is_logged_in_user(
+    auth_state: &mut AuthState,
+    username: &str,
+) -> Result<bool, AppError> {
+    Ok(auth_state
         .get_user()
-        .await
-        .map(|logged_in_user| logged_in_user.username == username)
-        .unwrap_or_default()
+        .await?
+        .is_some_and(|logged_in_user| logged_in_user.username == username))
 }
\ No newline at end of file
@@ -0,0 +1,67 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use hecksmosis::{get_router_with_store, init_database, repository, Config, ServerConfig};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+/// Runs the site as a plain server, without Shuttle. Settings come from the
+/// environment, or from a file of `NAME=value` lines for any that aren't set.
+#[derive(Parser)]
+struct Cli {
+    /// File to read settings from, in the same format as a `.env` file.
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+}
+
+/// Waits for Ctrl+C, or the SIGTERM sent by service managers and containers.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    info!("Shutting down");
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    if let Some(path) = &cli.config {
+        dotenvy::from_path(path)?;
+    }
+
+    let server_config = ServerConfig::from_env();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&server_config.log_level)?)
+        .init();
+
+    let config = Config::from_env();
+    let store = repository::connect(&server_config.database_url).await?;
+    init_database(&store, &config).await?;
+
+    info!("Listening on {}", server_config.bind_address);
+    let router = get_router_with_store(store, config);
+    axum::Server::bind(&server_config.bind_address)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    Ok(())
+}
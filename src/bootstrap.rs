@@ -0,0 +1,55 @@
+use tracing::info;
+
+use crate::{
+    config::BootstrapAdmin,
+    errors::BootstrapError,
+    password::PasswordHashing,
+    repository::{AdminBootstrap, Store},
+    validation::{valid_username, MIN_PASSWORD_LENGTH},
+};
+
+/// Makes the first admin, so that there is someone to promote everyone else
+/// from `/admin`. An existing user is promoted, otherwise one is created with
+/// the given password. Does nothing once any user can assign roles.
+pub(crate) async fn bootstrap_admin(
+    store: &Store,
+    hashing: &PasswordHashing,
+    admin: &BootstrapAdmin,
+) -> Result<(), BootstrapError> {
+    if !valid_username(&admin.username) {
+        return Err(BootstrapError::InvalidUsername);
+    }
+
+    // The password only matters when the user has to be created.
+    let password_hash = match &admin.password {
+        Some(password) if store.user_id(&admin.username).await?.is_none() => {
+            if password.len() < MIN_PASSWORD_LENGTH {
+                return Err(BootstrapError::InvalidPassword);
+            }
+
+            Some(
+                hashing
+                    .hash(password)
+                    .await
+                    .ok_or(BootstrapError::InvalidPassword)?,
+            )
+        }
+        _ => None,
+    };
+
+    match store
+        .bootstrap_admin(&admin.username, password_hash.as_deref())
+        .await?
+    {
+        AdminBootstrap::AdminExists => Err(BootstrapError::AdminExists),
+        AdminBootstrap::NoSuchUser => Err(BootstrapError::PasswordRequired),
+        AdminBootstrap::Promoted => {
+            info!("Promoted user '{}' to admin", admin.username);
+            Ok(())
+        }
+        AdminBootstrap::Created => {
+            info!("Created admin user '{}'", admin.username);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use argon2::Params;
+use cookie::SameSite;
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    errors::ConfigError,
+    mail::{transport_from_config, MemoryMailbox},
+};
+
+/// Runtime settings for the site, read from the environment.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Base URL used when building absolute links, such as in emails.
+    pub public_url: String,
+    /// Address that outgoing mail is sent from.
+    pub mail_from: String,
+    pub mail_transport: MailTransportConfig,
+    /// Cost of hashing new passwords. Existing hashes with a different cost are
+    /// upgraded the next time their user logs in.
+    pub argon2_params: Params,
+    pub cookies: CookieConfig,
+    /// Addresses of the reverse proxies in front of the site. Only these are
+    /// believed about the client's address in `X-Forwarded-For`. Empty by
+    /// default, which ignores the header.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Key for signing API access tokens. Without one, a random key is made at
+    /// startup and tokens stop working whenever the site restarts.
+    pub jwt_secret: Vec<u8>,
+    /// Account to make the first admin at startup, if no admin exists yet.
+    pub bootstrap_admin: Option<BootstrapAdmin>,
+}
+
+/// An existing user to promote, or a new one to create when a password is
+/// given.
+#[derive(Clone, Debug)]
+pub struct BootstrapAdmin {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// Attributes shared by every cookie the site sets.
+#[derive(Clone, Debug)]
+pub struct CookieConfig {
+    /// Only send cookies over HTTPS. Secure cookies also get the `__Host-`
+    /// prefix, which stops subdomains and plain HTTP pages from overwriting them.
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl CookieConfig {
+    /// The name a cookie is actually stored under.
+    pub fn name(&self, name: &str) -> String {
+        if self.secure {
+            format!("__Host-{}", name)
+        } else {
+            name.to_owned()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MailTransportConfig {
+    Smtp {
+        host: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// Writes every message to a file in `directory` instead of sending it.
+    File { directory: PathBuf },
+    /// Keeps every message in `MemoryMailbox`, for tests.
+    Memory(MemoryMailbox),
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        // Writing mail to files has to be asked for, so a missing SMTP_HOST
+        // can't quietly leave reset emails unsent.
+        let mail_transport = match (env::var("SMTP_HOST"), env::var("MAIL_DIRECTORY")) {
+            (Ok(host), _) => MailTransportConfig::Smtp {
+                host,
+                username: env::var("SMTP_USERNAME").ok(),
+                password: env::var("SMTP_PASSWORD").ok(),
+            },
+            (Err(_), Ok(directory)) => MailTransportConfig::File {
+                directory: directory.into(),
+            },
+            (Err(_), Err(_)) => panic!("SMTP_HOST or MAIL_DIRECTORY must be set"),
+        };
+
+        let argon2_params = argon2_params_from_env().unwrap_or_else(|error| panic!("{}", error));
+
+        let public_url =
+            env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8000".to_owned());
+
+        let cookies = CookieConfig {
+            secure: env::var("COOKIE_SECURE")
+                .map(|value| value.parse().expect("COOKIE_SECURE must be true or false"))
+                .unwrap_or_else(|_| public_url.starts_with("https://")),
+            same_site: match env::var("COOKIE_SAME_SITE").as_deref() {
+                Ok("strict") => SameSite::Strict,
+                Ok("lax") | Err(_) => SameSite::Lax,
+                Ok("none") => SameSite::None,
+                Ok(_) => panic!("COOKIE_SAME_SITE must be strict, lax or none"),
+            },
+        };
+
+        let trusted_proxies = env::var("TRUSTED_PROXIES")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|address| address.trim().parse().expect("invalid TRUSTED_PROXIES"))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let jwt_secret = env::var("JWT_SECRET")
+            .map(String::into_bytes)
+            .unwrap_or_else(|_| {
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            });
+
+        let bootstrap_admin = env::var("BOOTSTRAP_ADMIN_USERNAME")
+            .ok()
+            .map(|username| BootstrapAdmin {
+                username,
+                password: env::var("BOOTSTRAP_ADMIN_PASSWORD").ok(),
+            });
+
+        let config = Self {
+            public_url,
+            mail_from: env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "Hecksmosis <noreply@hecksmosis.shuttleapp.rs>".to_owned()),
+            mail_transport,
+            argon2_params,
+            cookies,
+            trusted_proxies,
+            jwt_secret,
+            bootstrap_admin,
+        };
+
+        if let Err(error) = transport_from_config(&config) {
+            panic!("invalid SMTP configuration: {}", error);
+        }
+
+        config
+    }
+}
+
+/// Settings for running the site as its own server, outside of Shuttle.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// A Postgres connection string, or a `sqlite:` URL to keep everything in
+    /// one SQLite file.
+    pub database_url: String,
+    pub bind_address: SocketAddr,
+    /// A `tracing` filter, such as `info` or `hecksmosis=debug,warn`.
+    pub log_level: String,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        Self {
+            database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            bind_address: env::var("BIND_ADDRESS")
+                .map(|address| address.parse().expect("invalid BIND_ADDRESS"))
+                .unwrap_or_else(|_| ([127, 0, 0, 1], 8000).into()),
+            log_level: env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_owned()),
+        }
+    }
+}
+
+/// The cost of hashing new passwords, from `ARGON2_MEMORY_KIB`,
+/// `ARGON2_ITERATIONS` and `ARGON2_PARALLELISM`. This is all the admin tool
+/// needs, so it can be read without the rest of [`Config`].
+pub fn argon2_params_from_env() -> Result<Params, ConfigError> {
+    Params::new(
+        env_number("ARGON2_MEMORY_KIB")?.unwrap_or(Params::DEFAULT_M_COST),
+        env_number("ARGON2_ITERATIONS")?.unwrap_or(Params::DEFAULT_T_COST),
+        env_number("ARGON2_PARALLELISM")?.unwrap_or(Params::DEFAULT_P_COST),
+        None,
+    )
+    .map_err(|error| ConfigError(format!("invalid Argon2 parameters: {}", error)))
+}
+
+fn env_number(name: &str) -> Result<Option<u32>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(number) => Ok(Some(number)),
+            Err(_) => Err(ConfigError(format!("{} must be a number", name))),
+        },
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,117 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::Limited;
+
+use crate::{
+    api::bearer_token,
+    auth::{AuthState, SessionToken},
+    errors::{AppError, CsrfError},
+};
+
+pub(crate) const CSRF_FIELD_NAME: &str = "csrf_token";
+const CSRF_HEADER_NAME: &str = "X-CSRF-Token";
+/// Matches the limit axum puts on form bodies.
+const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+
+/// Derives the CSRF token for a session. It can only be computed by someone who
+/// already knows the session token, and it doesn't reveal the session token.
+pub(crate) fn csrf_token(session_token: SessionToken) -> String {
+    sha256::digest(format!("csrf:{}", session_token.into_cookie_value()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Strips the scheme from an origin or URL, leaving `host[:port]`.
+fn authority(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    rest.split('/').next()
+}
+
+/// Checks that a request was sent by one of our own pages, using the `Origin`
+/// header or, failing that, the `Referer`. Requests carrying neither come from
+/// clients other than browsers and are allowed through.
+fn same_origin<B>(req: &Request<B>, public_url: &str) -> bool {
+    let headers = req.headers();
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|value| value.to_str().ok());
+
+    let Some(source) = source else {
+        return true;
+    };
+
+    let Some(source) = authority(source) else {
+        return false;
+    };
+
+    let host = headers.get(header::HOST).and_then(|value| value.to_str().ok());
+    Some(source) == host || Some(source) == authority(public_url)
+}
+
+async fn read_form_token(req: Request<Body>) -> Result<(Request<Body>, Option<String>), Response> {
+    let (parts, body) = req.into_parts();
+    let bytes: Bytes = hyper::body::to_bytes(Limited::new(body, MAX_FORM_SIZE))
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+
+    let token = form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == CSRF_FIELD_NAME)
+        .map(|(_, value)| value.into_owned());
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// Rejects state-changing requests that come from other sites. Requests made
+/// with a session must also carry the session's CSRF token, either in the
+/// `csrf_token` form field or the `X-CSRF-Token` header.
+pub(crate) async fn csrf(req: Request<Body>, next: Next<Body>, public_url: String) -> Response {
+    if matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        return next.run(req).await;
+    }
+
+    if !same_origin(&req, &public_url) {
+        return AppError::from(CsrfError::CrossOrigin).into_response();
+    }
+
+    let expected = req
+        .extensions()
+        .get::<AuthState>()
+        .and_then(AuthState::csrf_token);
+
+    // Bearer tokens are never sent by the browser on its own, so they can't be
+    // forged by another site.
+    let Some(expected) = expected.filter(|_| bearer_token(req.headers()).is_none()) else {
+        return next.run(req).await;
+    };
+
+    let header_token = req
+        .headers()
+        .get(CSRF_HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let (req, token) = match header_token {
+        Some(token) => (req, Some(token)),
+        None => match read_form_token(req).await {
+            Ok(result) => result,
+            Err(response) => return response,
+        },
+    };
+
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            next.run(req).await
+        }
+        _ => AppError::from(CsrfError::InvalidToken).into_response(),
+    }
+}
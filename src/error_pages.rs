@@ -0,0 +1,117 @@
+use axum::{
+    body::{self, Full},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tera::Context;
+use tracing::error;
+
+use crate::{errors::ErrorInfo, Templates};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// What an error response says, left on the response for [`render_errors`]
+/// to lay out once it knows what the client accepts.
+#[derive(Clone)]
+struct ErrorDetails {
+    status: StatusCode,
+    message: String,
+}
+
+/// A problem details object, as described in RFC 7807.
+#[derive(serde::Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'a str,
+}
+
+/// Builds the response for an error. Its body is plain text until
+/// [`render_errors`] replaces it with a page or a JSON problem.
+pub(crate) fn error_page(err: &dyn ErrorInfo) -> Response {
+    let (status, message) = err.error_info();
+
+    let mut response = (status, message.clone()).into_response();
+    response.extensions_mut().insert(ErrorDetails { status, message });
+    response
+}
+
+/// Whether the client asked for JSON rather than a page.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .any(|media_type| media_type == "application/json" || media_type == PROBLEM_JSON)
+}
+
+fn problem_body(details: &ErrorDetails) -> Option<(&'static str, String)> {
+    let problem = Problem {
+        problem_type: "about:blank",
+        title: details.status.canonical_reason().unwrap_or("Error"),
+        status: details.status.as_u16(),
+        detail: &details.message,
+    };
+
+    match serde_json::to_string(&problem) {
+        Ok(body) => Some((PROBLEM_JSON, body)),
+        Err(e) => {
+            error!("Could not serialize error: {}", e);
+            None
+        }
+    }
+}
+
+fn html_body(templates: &Templates, details: &ErrorDetails) -> Option<(&'static str, String)> {
+    let mut context = Context::new();
+    context.insert("status", &details.status.as_u16());
+    context.insert("title", &details.status.canonical_reason().unwrap_or("Error"));
+    context.insert("message", &details.message);
+
+    match templates.render("error", &context) {
+        Ok(body) => Some(("text/html; charset=utf-8", body)),
+        Err(e) => {
+            error!("Could not render error page: {}", e);
+            None
+        }
+    }
+}
+
+/// Turns the error responses made by [`error_page`] into an HTML page, or into
+/// a JSON problem for clients that accept JSON. Other responses, and any
+/// headers the error response already had, such as cookies, are left alone.
+pub(crate) async fn render_errors<B>(
+    req: Request<B>,
+    next: Next<B>,
+    templates: Templates,
+) -> Response {
+    let wants_json = accepts_json(req.headers());
+
+    let mut response = next.run(req).await;
+    let Some(details) = response.extensions_mut().remove::<ErrorDetails>() else {
+        return response;
+    };
+
+    let body = if wants_json {
+        problem_body(&details)
+    } else {
+        html_body(&templates, &details)
+    };
+
+    // If the body can't be built, the plain text one is still better than none.
+    let Some((content_type, body)) = body else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, body::boxed(Full::from(body)))
+}
@@ -1,7 +1,13 @@
 use std::{error::Error, fmt::Display};
-use axum::http::StatusCode;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tracing::{debug, error};
 
-pub trait ErrorInfo {
+use crate::{auth::Scope, error_pages::error_page};
+
+pub trait ErrorInfo: Error + Send + Sync + 'static {
     fn error_info(&self) -> (StatusCode, String);
 }
 
@@ -40,12 +46,13 @@ impl ErrorInfo for NotAdmin {
 }
 
 #[derive(Debug)]
-pub(crate) enum SignupError {
+pub enum SignupError {
     UsernameExists,
     InvalidUsername,
+    EmailExists,
+    InvalidEmail,
     PasswordsDoNotMatch,
     InvalidPassword,
-    InternalError,
 }
 
 impl Display for SignupError {
@@ -53,9 +60,22 @@ impl Display for SignupError {
         match self {
             SignupError::InvalidUsername => f.write_str("Invalid username"),
             SignupError::UsernameExists => f.write_str("Username already exists"),
+            SignupError::EmailExists => f.write_str("Email already in use"),
+            SignupError::InvalidEmail => f.write_str("Invalid email"),
             SignupError::PasswordsDoNotMatch => f.write_str("Passwords do not match"),
             SignupError::InvalidPassword => f.write_str("Invalid Password"),
-            SignupError::InternalError => f.write_str("Internal Error"),
+        }
+    }
+}
+
+impl SignupError {
+    /// The signup form field the error is about.
+    pub(crate) fn field(&self) -> &'static str {
+        match self {
+            SignupError::UsernameExists | SignupError::InvalidUsername => "username",
+            SignupError::EmailExists | SignupError::InvalidEmail => "email",
+            SignupError::PasswordsDoNotMatch => "confirm_password",
+            SignupError::InvalidPassword => "password",
         }
     }
 }
@@ -67,24 +87,36 @@ impl ErrorInfo for SignupError {
         match self {
             SignupError::InvalidUsername => (StatusCode::BAD_REQUEST, self.to_string()),
             SignupError::UsernameExists => (StatusCode::BAD_REQUEST, self.to_string()),
+            SignupError::EmailExists => (StatusCode::BAD_REQUEST, self.to_string()),
+            SignupError::InvalidEmail => (StatusCode::BAD_REQUEST, self.to_string()),
             SignupError::PasswordsDoNotMatch => (StatusCode::BAD_REQUEST, self.to_string()),
             SignupError::InvalidPassword => (StatusCode::BAD_REQUEST, self.to_string()),
-            SignupError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
         }
     }
 }
 
 #[derive(Debug)]
 pub(crate) enum LoginError {
-    UserDoesNotExist,
-    WrongPassword,
+    /// Either the user does not exist or the password is wrong. The two cases
+    /// are deliberately indistinguishable.
+    InvalidCredentials,
+    /// Too many recent failures; holds the number of seconds until the next
+    /// attempt is allowed.
+    TooManyAttempts(i64),
 }
 
 impl Display for LoginError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            LoginError::UserDoesNotExist => f.write_str("User does not exist"),
-            LoginError::WrongPassword => f.write_str("Wrong password"),
+            LoginError::InvalidCredentials => f.write_str("Invalid username or password"),
+            LoginError::TooManyAttempts(seconds) if *seconds > 60 => f.write_fmt(format_args!(
+                "Too many failed login attempts, try again in {} minutes",
+                (seconds + 59) / 60
+            )),
+            LoginError::TooManyAttempts(seconds) => f.write_fmt(format_args!(
+                "Too many failed login attempts, try again in {} seconds",
+                seconds
+            )),
         }
     }
 }
@@ -94,12 +126,159 @@ impl Error for LoginError {}
 impl ErrorInfo for LoginError {
     fn error_info(&self) -> (StatusCode, String) {
         match self {
-            LoginError::UserDoesNotExist => (StatusCode::BAD_REQUEST, self.to_string()),
-            LoginError::WrongPassword => (StatusCode::UNAUTHORIZED, self.to_string()),
+            LoginError::InvalidCredentials => (StatusCode::UNAUTHORIZED, self.to_string()),
+            LoginError::TooManyAttempts(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PasswordChangeError {
+    WrongPassword,
+    PasswordsDoNotMatch,
+    InvalidPassword,
+}
+
+impl Display for PasswordChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordChangeError::WrongPassword => f.write_str("Current password is incorrect"),
+            PasswordChangeError::PasswordsDoNotMatch => f.write_str("Passwords do not match"),
+            PasswordChangeError::InvalidPassword => f.write_str("Invalid Password"),
+        }
+    }
+}
+
+impl PasswordChangeError {
+    /// The password form field the error is about.
+    pub(crate) fn field(&self) -> &'static str {
+        match self {
+            PasswordChangeError::WrongPassword => "current_password",
+            PasswordChangeError::PasswordsDoNotMatch => "confirm_password",
+            PasswordChangeError::InvalidPassword => "password",
+        }
+    }
+}
+
+impl Error for PasswordChangeError {}
+
+impl ErrorInfo for PasswordChangeError {
+    fn error_info(&self) -> (StatusCode, String) {
+        match self {
+            PasswordChangeError::WrongPassword => (StatusCode::UNAUTHORIZED, self.to_string()),
+            PasswordChangeError::PasswordsDoNotMatch => (StatusCode::BAD_REQUEST, self.to_string()),
+            PasswordChangeError::InvalidPassword => (StatusCode::BAD_REQUEST, self.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EmailError {
+    EmailExists,
+    InvalidEmail,
+}
+
+impl Display for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailError::EmailExists => f.write_str("Email already in use"),
+            EmailError::InvalidEmail => f.write_str("Invalid email"),
+        }
+    }
+}
+
+impl Error for EmailError {}
+
+impl ErrorInfo for EmailError {
+    fn error_info(&self) -> (StatusCode, String) {
+        (StatusCode::BAD_REQUEST, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PasswordResetError {
+    InvalidToken,
+    PasswordsDoNotMatch,
+    InvalidPassword,
+    /// Seconds until another reset may be asked for.
+    TooManyRequests(i64),
+}
+
+impl Display for PasswordResetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordResetError::InvalidToken => {
+                f.write_str("This password reset link is invalid or has expired")
+            }
+            PasswordResetError::PasswordsDoNotMatch => f.write_str("Passwords do not match"),
+            PasswordResetError::InvalidPassword => f.write_str("Invalid Password"),
+            PasswordResetError::TooManyRequests(seconds) if *seconds > 60 => {
+                f.write_fmt(format_args!(
+                    "Too many password reset requests, try again in {} minutes",
+                    (seconds + 59) / 60
+                ))
+            }
+            PasswordResetError::TooManyRequests(seconds) => f.write_fmt(format_args!(
+                "Too many password reset requests, try again in {} seconds",
+                seconds
+            )),
+        }
+    }
+}
+
+impl Error for PasswordResetError {}
+
+impl ErrorInfo for PasswordResetError {
+    fn error_info(&self) -> (StatusCode, String) {
+        match self {
+            PasswordResetError::TooManyRequests(_) => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string())
+            }
+            _ => (StatusCode::BAD_REQUEST, self.to_string()),
         }
     }
 }
 
+#[derive(Debug)]
+pub(crate) enum TwoFactorError {
+    InvalidCode,
+    ChallengeExpired,
+    WrongPassword,
+}
+
+impl Display for TwoFactorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TwoFactorError::InvalidCode => f.write_str("Invalid authentication code"),
+            TwoFactorError::ChallengeExpired => f.write_str("Login expired, please log in again"),
+            TwoFactorError::WrongPassword => f.write_str("Wrong password"),
+        }
+    }
+}
+
+impl Error for TwoFactorError {}
+
+impl ErrorInfo for TwoFactorError {
+    fn error_info(&self) -> (StatusCode, String) {
+        match self {
+            TwoFactorError::InvalidCode => (StatusCode::UNAUTHORIZED, self.to_string()),
+            TwoFactorError::ChallengeExpired => (StatusCode::UNAUTHORIZED, self.to_string()),
+            TwoFactorError::WrongPassword => (StatusCode::UNAUTHORIZED, self.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MailError(pub String);
+
+impl Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("could not send mail: {}", self.0))
+    }
+}
+
+impl Error for MailError {}
+
 #[derive(Debug)]
 pub(crate) struct NoUser(pub String);
 
@@ -115,4 +294,317 @@ impl ErrorInfo for NoUser {
     fn error_info(&self) -> (StatusCode, String) {
         (StatusCode::NOT_FOUND, self.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub(crate) enum CsrfError {
+    CrossOrigin,
+    InvalidToken,
+}
+
+impl Display for CsrfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CsrfError::CrossOrigin => f.write_str("Cross-site request rejected"),
+            CsrfError::InvalidToken => f.write_str("Invalid or missing form token, please try again"),
+        }
+    }
+}
+
+impl Error for CsrfError {}
+
+impl ErrorInfo for CsrfError {
+    fn error_info(&self) -> (StatusCode, String) {
+        (StatusCode::FORBIDDEN, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TokenError {
+    SecondFactorRequired,
+    InvalidRefreshToken,
+    /// The session was logged out while its tokens were being issued.
+    SessionEnded,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::SecondFactorRequired => f.write_str("Authentication code required"),
+            TokenError::InvalidRefreshToken => f.write_str("Invalid or expired refresh token"),
+            TokenError::SessionEnded => f.write_str("The session has ended, please log in again"),
+        }
+    }
+}
+
+impl Error for TokenError {}
+
+impl ErrorInfo for TokenError {
+    fn error_info(&self) -> (StatusCode, String) {
+        (StatusCode::UNAUTHORIZED, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenNameError;
+
+impl Display for TokenNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Token name must be between 1 and 100 characters")
+    }
+}
+
+impl Error for TokenNameError {}
+
+impl ErrorInfo for TokenNameError {
+    fn error_info(&self) -> (StatusCode, String) {
+        (StatusCode::BAD_REQUEST, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MissingScope(pub Scope);
+
+impl Display for MissingScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Token is missing the '{}' scope", self.0.as_str()))
+    }
+}
+
+impl Error for MissingScope {}
+
+impl ErrorInfo for MissingScope {
+    fn error_info(&self) -> (StatusCode, String) {
+        (StatusCode::FORBIDDEN, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum RoleError {
+    LastAdmin,
+    ConfirmationRequired,
+}
+
+impl Display for RoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoleError::LastAdmin => f.write_str("There must always be at least one admin"),
+            RoleError::ConfirmationRequired => {
+                f.write_str("Confirm the role change by ticking the box next to it")
+            }
+        }
+    }
+}
+
+impl Error for RoleError {}
+
+impl ErrorInfo for RoleError {
+    fn error_info(&self) -> (StatusCode, String) {
+        match self {
+            RoleError::LastAdmin => (StatusCode::CONFLICT, self.to_string()),
+            RoleError::ConfirmationRequired => (StatusCode::BAD_REQUEST, self.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+    AdminExists,
+    InvalidUsername,
+    /// The user doesn't exist yet, and creating them needs a password.
+    PasswordRequired,
+    InvalidPassword,
+    Database(AppError),
+}
+
+impl Display for BootstrapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootstrapError::AdminExists => f.write_str("An admin already exists"),
+            BootstrapError::InvalidUsername => f.write_str("Invalid username"),
+            BootstrapError::PasswordRequired => {
+                f.write_str("The user does not exist, so a password is needed to create them")
+            }
+            BootstrapError::InvalidPassword => f.write_str("Invalid password"),
+            BootstrapError::Database(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl Error for BootstrapError {}
+
+impl From<AppError> for BootstrapError {
+    fn from(error: AppError) -> Self {
+        BootstrapError::Database(error)
+    }
+}
+
+/// A setting in the environment that can't be used.
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Stops the site from starting.
+#[derive(Debug)]
+pub enum StartupError {
+    Migrate(sqlx::migrate::MigrateError),
+    Bootstrap(BootstrapError),
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::Migrate(error) => write!(f, "Could not migrate the database: {}", error),
+            StartupError::Bootstrap(error) => write!(f, "Could not create the first admin: {}", error),
+        }
+    }
+}
+
+impl Error for StartupError {}
+
+/// Anything a handler can fail with. Database, template, TOTP and signing
+/// errors are logged and only shown to the user as a generic message; every
+/// other error carries a message written for the user.
+#[derive(Debug)]
+pub enum AppError {
+    Database(sqlx::Error),
+    Template(tera::Error),
+    /// A stored two-factor secret couldn't be used.
+    Totp(totp_rs::TotpUrlError),
+    /// An API access token couldn't be signed.
+    Token(jsonwebtoken::errors::Error),
+    /// The request isn't logged in, or isn't allowed to do this.
+    Auth(Box<dyn ErrorInfo>),
+    NotFound(Box<dyn ErrorInfo>),
+    /// The request was refused, for example because a form was filled in wrong.
+    Rejected(Box<dyn ErrorInfo>),
+}
+
+impl AppError {
+    /// The user-facing error this wraps, if it is an `E`.
+    pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+        match self {
+            AppError::Auth(error) | AppError::NotFound(error) | AppError::Rejected(error) => {
+                (error.as_ref() as &dyn Error).downcast_ref()
+            }
+            AppError::Database(_)
+            | AppError::Template(_)
+            | AppError::Totp(_)
+            | AppError::Token(_) => None,
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Database(error) => write!(f, "Database error: {}", error),
+            AppError::Totp(error) => write!(f, "TOTP error: {}", error),
+            AppError::Token(error) => write!(f, "Token error: {}", error),
+            AppError::Template(error) => {
+                // Tera keeps the useful part, such as the missing variable, in
+                // the source chain.
+                write!(f, "Template error: {}", error)?;
+                let mut source = error.source();
+                while let Some(cause) = source {
+                    write!(f, ": {}", cause)?;
+                    source = cause.source();
+                }
+                Ok(())
+            }
+            AppError::Auth(error) | AppError::NotFound(error) | AppError::Rejected(error) => {
+                Display::fmt(error, f)
+            }
+        }
+    }
+}
+
+impl Error for AppError {}
+
+impl ErrorInfo for AppError {
+    fn error_info(&self) -> (StatusCode, String) {
+        match self {
+            AppError::Database(_)
+            | AppError::Template(_)
+            | AppError::Totp(_)
+            | AppError::Token(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, please try again later".to_owned(),
+            ),
+            AppError::Auth(error) | AppError::NotFound(error) | AppError::Rejected(error) => {
+                error.error_info()
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match &self {
+            AppError::Database(_)
+            | AppError::Template(_)
+            | AppError::Totp(_)
+            | AppError::Token(_) => error!("{}", self),
+            _ => debug!("Request refused: {}", self),
+        }
+
+        error_page(&self)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        AppError::Database(error)
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(error: tera::Error) -> Self {
+        AppError::Template(error)
+    }
+}
+
+impl From<totp_rs::TotpUrlError> for AppError {
+    fn from(error: totp_rs::TotpUrlError) -> Self {
+        AppError::Totp(error)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        AppError::Token(error)
+    }
+}
+
+macro_rules! app_error_from {
+    ($variant:ident: $($error:ty),+) => {
+        $(
+            impl From<$error> for AppError {
+                fn from(error: $error) -> Self {
+                    AppError::$variant(Box::new(error))
+                }
+            }
+        )+
+    };
+}
+
+app_error_from!(Auth: NotLoggedIn, NotAdmin, MissingScope, CsrfError);
+app_error_from!(NotFound: NoUser);
+app_error_from!(
+    Rejected: SignupError,
+    LoginError,
+    PasswordChangeError,
+    EmailError,
+    PasswordResetError,
+    TwoFactorError,
+    TokenError,
+    TokenNameError,
+    RoleError
+);
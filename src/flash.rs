@@ -0,0 +1,155 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{header::SET_COOKIE, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    config::CookieConfig,
+    utils::{clear_flash_cookie, flash_cookie, get_cookie},
+    FLASH_COOKIE_NAME,
+};
+
+#[derive(Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    Success,
+    Warning,
+    Error,
+}
+
+impl Level {
+    const ALL: [Level; 3] = [Level::Success, Level::Warning, Level::Error];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Success => "success",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Level::ALL
+            .into_iter()
+            .find(|level| level.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// A message for the user about something a request did.
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct Notice {
+    level: Level,
+    message: String,
+}
+
+#[derive(Default)]
+struct FlashState {
+    /// Left by earlier requests, to be shown on the next page rendered.
+    pending: Vec<Notice>,
+    /// Whether `pending` made it onto a page.
+    shown: bool,
+    /// Left by this request.
+    added: Vec<Notice>,
+}
+
+/// Notices that handlers leave for the next page the user sees, usually the
+/// one they are redirected to. They are carried over in a cookie by [`flash`].
+#[derive(Clone, Default)]
+pub(crate) struct Flashes(Arc<Mutex<FlashState>>);
+
+impl Flashes {
+    pub fn success(&self, message: impl Into<String>) {
+        self.push(Level::Success, message.into());
+    }
+
+    pub fn warning(&self, message: impl Into<String>) {
+        self.push(Level::Warning, message.into());
+    }
+
+    pub fn error(&self, message: impl Into<String>) {
+        self.push(Level::Error, message.into());
+    }
+
+    fn push(&self, level: Level, message: String) {
+        self.0.lock().unwrap().added.push(Notice { level, message });
+    }
+
+    /// Takes the notices to show on the page being rendered. Each one is only
+    /// shown once.
+    pub fn take(&self) -> Vec<Notice> {
+        let mut state = self.0.lock().unwrap();
+        state.shown = true;
+        state.pending.clone()
+    }
+}
+
+fn to_cookie_value(notices: &[Notice]) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(notices.iter().map(|notice| (notice.level.as_str(), &notice.message)))
+        .finish()
+}
+
+fn from_cookie_value(cookie_value: &str) -> Vec<Notice> {
+    form_urlencoded::parse(cookie_value.as_bytes())
+        .filter_map(|(level, message)| {
+            Some(Notice {
+                level: level.parse().ok()?,
+                message: message.into_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Gives handlers the request's [`Flashes`] and keeps the flash cookie up to
+/// date afterwards. Notices that weren't shown, for example because the
+/// response was a redirect or an error, are kept for the page after.
+pub(crate) async fn flash<B>(mut req: Request<B>, next: Next<B>, cookies: CookieConfig) -> Response {
+    let pending = get_cookie(req.headers(), &cookies.name(FLASH_COOKIE_NAME))
+        .map(|cookie_value| from_cookie_value(&cookie_value))
+        .unwrap_or_default();
+    let had_pending = !pending.is_empty();
+
+    let flashes = Flashes(Arc::new(Mutex::new(FlashState {
+        pending,
+        ..FlashState::default()
+    })));
+    req.extensions_mut().insert(flashes.clone());
+
+    let mut response = next.run(req).await;
+
+    let FlashState {
+        mut pending,
+        shown,
+        added,
+    } = std::mem::take(&mut *flashes.0.lock().unwrap());
+    if shown {
+        pending.clear();
+    } else if added.is_empty() {
+        return response;
+    }
+    pending.extend(added);
+
+    let cookie = if !pending.is_empty() {
+        flash_cookie(&cookies, to_cookie_value(&pending))
+    } else if had_pending {
+        clear_flash_cookie(&cookies)
+    } else {
+        return response;
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(SET_COOKIE, value);
+    }
+    response
+}
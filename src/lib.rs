@@ -0,0 +1,540 @@
+pub mod admin;
+mod api;
+mod auth;
+mod bootstrap;
+mod config;
+mod csrf;
+mod error_pages;
+mod errors;
+mod flash;
+mod mail;
+mod metrics;
+mod password;
+pub mod repository;
+mod reset;
+mod roles;
+mod sessions;
+mod throttle;
+mod tokens;
+mod two_factor;
+mod users;
+mod utils;
+mod validation;
+
+use api::{post_token, TokenKeys};
+use bootstrap::bootstrap_admin;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+pub use config::{argon2_params_from_env, Config, CookieConfig, MailTransportConfig, ServerConfig};
+use csrf::csrf;
+use error_pages::render_errors;
+use flash::{flash, Flashes};
+pub use mail::{Mail, MemoryMailbox};
+use mail::{transport_from_config, MailTransport, UnavailableMailTransport};
+use metrics::metrics;
+use password::PasswordHashing;
+use repository::{PostgresStore, Store};
+use reset::{get_forgot_password, get_reset_password, post_forgot_password, post_reset_password};
+use sessions::{revoke_session, sessions};
+use tokens::{create_token, revoke_token, tokens};
+use two_factor::{
+    disable_two_factor, enable_two_factor, get_two_factor_login, post_two_factor_login,
+    regenerate_recovery_codes, setup_two_factor, two_factor,
+};
+use users::{me, profile, email, user, users, admin, add_admin, remove_admin};
+
+use axum::{
+    extract::Extension,
+    http::{self, Response},
+    middleware,
+    response::{Html, IntoResponse, Redirect},
+    routing::{any, get, post},
+    Form, Router,
+};
+
+use auth::{
+    auth, change_password, delete_all_sessions, delete_session, delete_user, login, signup,
+    AuthState, ClientInfo, LoginOutcome, Scope,
+};
+pub use errors::{ConfigError, StartupError};
+use errors::{
+    AppError, BootstrapError, ErrorInfo, LoginError, MissingScope, NotLoggedIn,
+    PasswordChangeError, SignupError,
+};
+use pbkdf2::password_hash::rand_core::OsRng;
+use rand_chacha::ChaCha8Rng;
+use rand_core::{RngCore, SeedableRng};
+use sqlx::migrate::{MigrateError, Migrator};
+use tera::Tera;
+use tracing::{error, info};
+use utils::*;
+use validation::{
+    check_email, check_password, check_username, form_context, normalize_email, FieldErrors,
+};
+
+type Templates = Arc<Tera>;
+type Database = sqlx::PgPool;
+type Random = Arc<Mutex<ChaCha8Rng>>;
+type Mailer = Arc<dyn MailTransport>;
+
+const SESSION_COOKIE_NAME: &str = "session";
+/// The session cookie's name before it was hardened. It is still accepted so
+/// that existing sessions carry over to the new cookie.
+const LEGACY_SESSION_COOKIE_NAME: &str = "user_token";
+const LOGIN_CHALLENGE_COOKIE_NAME: &str = "login_challenge";
+/// How long a user has to enter their second factor after their password.
+const LOGIN_CHALLENGE_LIFETIME_SECONDS: i64 = 60 * 5;
+const FLASH_COOKIE_NAME: &str = "flash";
+/// How long a notice waits to be shown on the next page.
+const FLASH_LIFETIME_SECONDS: i64 = 60;
+/// Sessions expire after this many seconds without being used.
+const SESSION_LIFETIME_SECONDS: i64 = 60 * 60 * 24 * 14;
+
+/// The migrations in `migrations/`, applied in order of their version. Applied
+/// versions are recorded in the `_sqlx_migrations` table.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Brings the database up to the latest migration.
+pub async fn run_migrations(database: &Database) -> Result<(), MigrateError> {
+    MIGRATOR.run(database).await
+}
+
+/// Migrates the store and, if configured, creates the first admin. Run this
+/// before serving requests.
+pub async fn init_database(store: &Store, config: &Config) -> Result<(), StartupError> {
+    store.migrate().await.map_err(StartupError::Migrate)?;
+
+    if let Some(admin) = &config.bootstrap_admin {
+        let hashing = PasswordHashing::new(config.argon2_params.clone());
+        match bootstrap_admin(store, &hashing, admin).await {
+            Ok(()) => {}
+            // The variables are usually left set after the first start.
+            Err(BootstrapError::AdminExists) => {
+                info!("An admin already exists, ignoring BOOTSTRAP_ADMIN_USERNAME")
+            }
+            Err(error) => return Err(StartupError::Bootstrap(error)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets up the site on Shuttle's database the way the `hecksmosis` binary
+/// runs it: migrated, with the first admin made if configured.
+pub async fn shuttle_service(
+    database: Database,
+    config: Config,
+) -> Result<ShuttleService, StartupError> {
+    let store: Store = Arc::new(PostgresStore::new(database));
+    init_database(&store, &config).await?;
+
+    Ok(ShuttleService(get_router_with_store(store, config)))
+}
+
+/// Serves the site on Shuttle. Unlike the service from `shuttle_axum`, this
+/// tells handlers which address each connection came from, which throttling
+/// and the sessions page need.
+pub struct ShuttleService(pub Router);
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for ShuttleService {
+    async fn bind(self, address: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        axum::Server::bind(&address)
+            .serve(self.0.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .map_err(shuttle_runtime::CustomError::new)?;
+
+        Ok(())
+    }
+}
+
+pub fn get_router(database: Database, config: Config) -> Router {
+    get_router_with_store(Arc::new(PostgresStore::new(database)), config)
+}
+
+/// Builds the site on top of `store`, which may be any of the stores in
+/// [`repository`]. Migrating it with [`init_database`] is up to the caller.
+pub fn get_router_with_store(store: Store, config: Config) -> Router {
+    let mut tera = Tera::default();
+    tera.add_raw_templates(vec![
+        ("base.html", include_str!("../templates/base.html")),
+        ("csrf_field", include_str!("../templates/csrf_field.html")),
+        ("error", include_str!("../templates/error.html")),
+        ("admin", include_str!("../templates/admin.html")),
+        ("index", include_str!("../templates/index.html")),
+        ("signup", include_str!("../templates/signup.html")),
+        ("login", include_str!("../templates/login.html")),
+        ("users", include_str!("../templates/users.html")),
+        ("user", include_str!("../templates/user.html")),
+        ("sessions", include_str!("../templates/sessions.html")),
+        ("password", include_str!("../templates/password.html")),
+        ("forgot_password", include_str!("../templates/forgot_password.html")),
+        ("reset_password", include_str!("../templates/reset_password.html")),
+        ("two_factor", include_str!("../templates/two_factor.html")),
+        ("two_factor_login", include_str!("../templates/two_factor_login.html")),
+        ("tokens", include_str!("../templates/tokens.html")),
+    ])
+    .unwrap();
+    let templates: Templates = Arc::new(tera);
+
+    let error_templates = templates.clone();
+    let middleware_store = store.clone();
+    let cookies = config.cookies.clone();
+    let flash_cookies = config.cookies.clone();
+    let keys = TokenKeys::new(&config.jwt_secret);
+    let middleware_keys = keys.clone();
+    let trusted_proxies = config.trusted_proxies.clone();
+    let public_url = config.public_url.clone();
+    let random = ChaCha8Rng::seed_from_u64(OsRng.next_u64());
+    let mailer = transport_from_config(&config).unwrap_or_else(|error| {
+        error!("No mail will be sent: {}", error);
+        Arc::new(UnavailableMailTransport(error.to_string()))
+    });
+    let hashing = PasswordHashing::new(config.argon2_params.clone());
+
+    Router::new()
+        .route("/", get(index))
+        .route("/signup", get(get_signup).post(post_signup))
+        .route("/login", get(get_login).post(post_login))
+        .route("/login/2fa", get(get_two_factor_login).post(post_two_factor_login))
+        .route("/forgot-password", get(get_forgot_password).post(post_forgot_password))
+        .route("/reset-password", get(get_reset_password).post(post_reset_password))
+        .route("/logout", post(post_logout))
+        .route("/logout/all", post(post_logout_everywhere))
+        .route("/delete", post(post_delete))
+        .route("/me", get(me))
+        .route("/me/password", get(get_password).post(post_password))
+        .route("/me/email", post(email))
+        .route("/me/2fa", get(two_factor))
+        .route("/me/2fa/setup", post(setup_two_factor))
+        .route("/me/2fa/enable", post(enable_two_factor))
+        .route("/me/2fa/disable", post(disable_two_factor))
+        .route("/me/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/me/sessions", get(sessions))
+        .route("/me/sessions/:id/revoke", post(revoke_session))
+        .route("/me/tokens", get(tokens).post(create_token))
+        .route("/me/tokens/:id/revoke", post(revoke_token))
+        .route("/user/:username", get(user))
+        .route("/profile", post(profile))
+        .route("/users", get(users))
+        .route("/admin", get(admin))
+        .route("/admin/add/:username", post(add_admin))
+        .route("/admin/remove/:username", post(remove_admin))
+        .route("/metrics", get(metrics))
+        .route("/api/token", post(post_token))
+        .route("/styles.css", any(styles))
+        .layer(middleware::from_fn(move |req, next| {
+            flash(req, next, flash_cookies.clone())
+        }))
+        .layer(middleware::from_fn(move |req, next| {
+            csrf(req, next, public_url.clone())
+        }))
+        .layer(middleware::from_fn(move |req, next| {
+            auth(
+                req,
+                next,
+                middleware_store.clone(),
+                cookies.clone(),
+                middleware_keys.clone(),
+                trusted_proxies.clone(),
+            )
+        }))
+        .layer(middleware::from_fn(move |req, next| {
+            render_errors(req, next, error_templates.clone())
+        }))
+        .layer(Extension(templates))
+        .layer(Extension(store))
+        .layer(Extension(Arc::new(Mutex::new(random))))
+        .layer(Extension(mailer))
+        .layer(Extension(hashing))
+        .layer(Extension(keys))
+        .layer(Extension(Arc::new(config)))
+}
+
+async fn index(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut context = page_context(&current_user, &flashes);
+    context.insert("home_screen", &true);
+    Ok(Html(templates.render("index", &context)?))
+}
+
+async fn get_signup(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    let context =
+        form_context(&current_user, &flashes, &SignupForm::default(), &FieldErrors::default());
+    Ok(Html(templates.render("signup", &context)?))
+}
+
+async fn get_login(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    let context =
+        form_context(&current_user, &flashes, &LoginForm::default(), &FieldErrors::default());
+    Ok(Html(templates.render("login", &context)?))
+}
+
+/// Problems with the form are shown on it again, keeping what was entered
+/// apart from the passwords.
+#[allow(clippy::too_many_arguments)]
+async fn post_signup(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Extension(config): Extension<Arc<Config>>,
+    Form(form): Form<SignupForm>,
+) -> Result<impl IntoResponse, AppError> {
+    let email = normalize_email(&form.email);
+    let email = email.as_deref();
+
+    let mut errors = FieldErrors::default();
+    errors.check("username", check_username(&form.username));
+    errors.check("email", check_email(email));
+    errors.check("password", check_password(&form.password));
+    if form.password != form.confirm_password {
+        errors.add("confirm_password", SignupError::PasswordsDoNotMatch.to_string());
+    }
+
+    if errors.is_empty() {
+        let signed_up =
+            signup(&store, random, &hashing, &client_info, &form.username, email, &form.password)
+                .await;
+
+        match signed_up {
+            Ok(session_token) => {
+                flashes.success(format!("Welcome, {}! Your account has been created", form.username));
+                if email.is_none() {
+                    flashes.warning("Add an email address to be able to reset a forgotten password");
+                }
+
+                return Ok(login_response(&config.cookies, session_token).into_response());
+            }
+            Err(error) => match error.downcast_ref::<SignupError>() {
+                Some(signup_error) => errors.add(signup_error.field(), signup_error.to_string()),
+                None => return Err(error),
+            },
+        }
+    }
+
+    let context = form_context(&current_user, &flashes, &form, &errors);
+    let page = templates.render("signup", &context)?;
+    Ok((http::StatusCode::BAD_REQUEST, Html(page)).into_response())
+}
+
+/// Failed logins show the form again, keeping the username.
+#[allow(clippy::too_many_arguments)]
+async fn post_login(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Extension(config): Extension<Arc<Config>>,
+    Form(form): Form<LoginForm>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut errors = FieldErrors::default();
+    if form.username.is_empty() {
+        errors.add("username", "Enter your username");
+    }
+    if form.password.is_empty() {
+        errors.add("password", "Enter your password");
+    }
+
+    let status = if !errors.is_empty() {
+        http::StatusCode::BAD_REQUEST
+    } else {
+        let username = form.username.clone();
+        let password = form.password.clone();
+        match login(&store, random, &hashing, &client_info, username, password).await {
+            Ok(LoginOutcome::Session(session_token)) => {
+                return Ok(login_response(&config.cookies, session_token).into_response());
+            }
+            Ok(LoginOutcome::SecondFactorRequired(challenge)) => {
+                return Ok(second_factor_response(&config.cookies, &challenge).into_response());
+            }
+            Err(error) => match error.downcast_ref::<LoginError>() {
+                Some(login_error) => {
+                    let (status, message) = login_error.error_info();
+                    errors.add("form", message);
+                    status
+                }
+                None => return Err(error),
+            },
+        }
+    };
+
+    let context = form_context(&current_user, &flashes, &form, &errors);
+    let page = templates.render("login", &context)?;
+    Ok((status, Html(page)).into_response())
+}
+
+async fn get_password(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.logged_in() {
+        return Err(NotLoggedIn.into());
+    }
+
+    let context =
+        form_context(&current_user, &flashes, &PasswordForm::default(), &FieldErrors::default());
+    Ok(Html(templates.render("password", &context)?))
+}
+
+/// Rejected changes show the form again with what was wrong.
+async fn post_password(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Form(form): Form<PasswordForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.logged_in() {
+        return Err(NotLoggedIn.into());
+    }
+
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    let mut errors = FieldErrors::default();
+    errors.check("password", check_password(&form.password));
+    if form.password != form.confirm_password {
+        errors.add("confirm_password", PasswordChangeError::PasswordsDoNotMatch.to_string());
+    }
+
+    let status = if !errors.is_empty() {
+        http::StatusCode::BAD_REQUEST
+    } else {
+        let changed = change_password(
+            current_user.clone(),
+            &hashing,
+            &client_info,
+            &form.current_password,
+            &form.password,
+        )
+        .await;
+
+        match changed {
+            Ok(()) => {
+                flashes.success("Your password has been changed");
+                return Ok(Redirect::to("/me").into_response());
+            }
+            Err(error) => {
+                if let Some(change_error) = error.downcast_ref::<PasswordChangeError>() {
+                    let (status, message) = change_error.error_info();
+                    errors.add(change_error.field(), message);
+                    status
+                } else if let Some(login_error) = error.downcast_ref::<LoginError>() {
+                    let (status, message) = login_error.error_info();
+                    errors.add("form", message);
+                    status
+                } else {
+                    return Err(error);
+                }
+            }
+        }
+    };
+
+    let context = form_context(&current_user, &flashes, &form, &errors);
+    let page = templates.render("password", &context)?;
+    Ok((status, Html(page)).into_response())
+}
+
+async fn post_logout(
+    Extension(current_user): Extension<AuthState>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, AppError> {
+    delete_session(current_user).await?;
+
+    Ok(logout_response(&config.cookies).await)
+}
+
+async fn post_logout_everywhere(
+    Extension(current_user): Extension<AuthState>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.logged_in() {
+        return Err(NotLoggedIn.into());
+    }
+
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    delete_all_sessions(current_user).await?;
+
+    Ok(logout_response(&config.cookies).await)
+}
+
+async fn post_delete(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.logged_in() {
+        return Err(NotLoggedIn.into());
+    }
+
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    delete_user(current_user).await?;
+    flashes.success("Your account has been deleted");
+
+    Ok(logout_response(&config.cookies).await)
+}
+
+async fn styles() -> impl IntoResponse {
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "text/css")
+        .body(include_str!("../public/styles.css").to_owned())
+        .unwrap()
+}
+
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct LoginForm {
+    username: String,
+    #[serde(skip_serializing)]
+    password: String,
+}
+
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct SignupForm {
+    username: String,
+    #[serde(default)]
+    email: String,
+    #[serde(skip_serializing)]
+    password: String,
+    #[serde(skip_serializing)]
+    confirm_password: String,
+}
+
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct PasswordForm {
+    #[serde(skip_serializing)]
+    current_password: String,
+    #[serde(skip_serializing)]
+    password: String,
+    #[serde(skip_serializing)]
+    confirm_password: String,
+}
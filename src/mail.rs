@@ -0,0 +1,166 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use time::OffsetDateTime;
+use tokio::fs;
+
+use crate::{
+    config::{Config, MailTransportConfig},
+    errors::MailError,
+    Mailer,
+};
+
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers outgoing mail. Implementations decide where the message ends up.
+#[async_trait]
+pub(crate) trait MailTransport: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), MailError>;
+}
+
+pub(crate) struct SmtpMailTransport {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailTransport {
+    pub fn new(
+        host: &str,
+        username: Option<String>,
+        password: Option<String>,
+        from: &str,
+    ) -> Result<Self, MailError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| MailError(e.to_string()))?;
+        if let (Some(username), Some(password)) = (username, password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse().map_err(|_| MailError(format!("invalid sender '{}'", from)))?,
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailTransport {
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail
+                .to
+                .parse()
+                .map_err(|_| MailError(format!("invalid recipient '{}'", mail.to)))?)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| MailError(e.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| MailError(e.to_string()))
+    }
+}
+
+/// Writes each message to its own file, for running the site locally without
+/// a mail server.
+pub(crate) struct FileMailTransport {
+    directory: PathBuf,
+    from: String,
+}
+
+impl FileMailTransport {
+    pub fn new(directory: PathBuf, from: &str) -> Self {
+        Self {
+            directory,
+            from: from.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileMailTransport {
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        fs::create_dir_all(&self.directory)
+            .await
+            .map_err(|e| MailError(e.to_string()))?;
+
+        let path = self
+            .directory
+            .join(format!("{}.eml", OffsetDateTime::now_utc().unix_timestamp_nanos()));
+        let contents = format!(
+            "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+            self.from, mail.to, mail.subject, mail.body
+        );
+
+        fs::write(path, contents)
+            .await
+            .map_err(|e| MailError(e.to_string()))
+    }
+}
+
+/// Keeps every message instead of sending it, so tests can read what the site
+/// sent. Clones share the same messages.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMailbox(Arc<Mutex<Vec<Mail>>>);
+
+impl MemoryMailbox {
+    /// Every message sent so far, oldest first.
+    pub fn messages(&self) -> Vec<Mail> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl MailTransport for MemoryMailbox {
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        self.0.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
+/// Stands in for a transport that couldn't be set up, failing every message
+/// with the reason.
+pub(crate) struct UnavailableMailTransport(pub String);
+
+#[async_trait]
+impl MailTransport for UnavailableMailTransport {
+    async fn send(&self, _mail: Mail) -> Result<(), MailError> {
+        Err(MailError(self.0.clone()))
+    }
+}
+
+/// Fails if the SMTP settings are invalid. [`Config::from_env`] checks this
+/// up front, so only hand-built configurations get this far with bad ones.
+pub(crate) fn transport_from_config(config: &Config) -> Result<Mailer, MailError> {
+    Ok(match &config.mail_transport {
+        MailTransportConfig::Smtp {
+            host,
+            username,
+            password,
+        } => Arc::new(SmtpMailTransport::new(
+            host,
+            username.clone(),
+            password.clone(),
+            &config.mail_from,
+        )?),
+        MailTransportConfig::File { directory } => {
+            Arc::new(FileMailTransport::new(directory.clone(), &config.mail_from))
+        }
+        MailTransportConfig::Memory(mailbox) => Arc::new(mailbox.clone()),
+    })
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+
+use crate::{
+    auth::AuthState,
+    errors::{AppError, NotAdmin},
+    password::HashAlgorithm,
+    roles::Permission,
+};
+
+static PASSWORDS_VERIFIED_ARGON2ID: AtomicU64 = AtomicU64::new(0);
+static PASSWORDS_VERIFIED_PBKDF2: AtomicU64 = AtomicU64::new(0);
+static PASSWORDS_REHASHED_ARGON2ID: AtomicU64 = AtomicU64::new(0);
+static PASSWORDS_REHASHED_PBKDF2: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn record_password_verified(algorithm: HashAlgorithm) {
+    match algorithm {
+        HashAlgorithm::Argon2id => &PASSWORDS_VERIFIED_ARGON2ID,
+        HashAlgorithm::Pbkdf2 => &PASSWORDS_VERIFIED_PBKDF2,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a stored hash that was replaced, by the algorithm it was made with.
+pub(crate) fn record_password_rehashed(from: HashAlgorithm) {
+    match from {
+        HashAlgorithm::Argon2id => &PASSWORDS_REHASHED_ARGON2ID,
+        HashAlgorithm::Pbkdf2 => &PASSWORDS_REHASHED_PBKDF2,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Serves the counters in the Prometheus text format.
+pub(crate) async fn metrics(
+    Extension(mut auth_state): Extension<AuthState>,
+) -> Result<impl IntoResponse, AppError> {
+    if !auth_state.has_permission(Permission::ViewMetrics).await? {
+        return Err(NotAdmin.into());
+    }
+
+    let body = format!(
+        "# HELP password_verifications_total Successful password checks by hash algorithm.
+# TYPE password_verifications_total counter
+password_verifications_total{{algorithm=\"argon2id\"}} {}
+password_verifications_total{{algorithm=\"pbkdf2\"}} {}
+# HELP password_rehashes_total Stored password hashes upgraded, by previous algorithm.
+# TYPE password_rehashes_total counter
+password_rehashes_total{{algorithm=\"argon2id\"}} {}
+password_rehashes_total{{algorithm=\"pbkdf2\"}} {}
+",
+        PASSWORDS_VERIFIED_ARGON2ID.load(Ordering::Relaxed),
+        PASSWORDS_VERIFIED_PBKDF2.load(Ordering::Relaxed),
+        PASSWORDS_REHASHED_ARGON2ID.load(Ordering::Relaxed),
+        PASSWORDS_REHASHED_PBKDF2.load(Ordering::Relaxed),
+    );
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    ))
+}
@@ -0,0 +1,137 @@
+use std::fmt::Display;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use pbkdf2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Pbkdf2,
+};
+use rand_core::OsRng;
+use tokio::task::spawn_blocking;
+
+use crate::metrics;
+
+/// The algorithm a stored password hash was made with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HashAlgorithm {
+    Argon2id,
+    /// Hashes created before the switch to Argon2id.
+    Pbkdf2,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Argon2id => f.write_str("argon2id"),
+            HashAlgorithm::Pbkdf2 => f.write_str("pbkdf2"),
+        }
+    }
+}
+
+pub(crate) struct VerifiedPassword {
+    pub algorithm: HashAlgorithm,
+    /// The hash was made with an older algorithm or different cost and should
+    /// be replaced with a fresh one.
+    pub needs_rehash: bool,
+}
+
+/// Hashes new passwords with Argon2id and verifies both Argon2id and legacy
+/// PBKDF2 hashes.
+#[derive(Clone)]
+pub(crate) struct PasswordHashing {
+    argon2: Argon2<'static>,
+    params: Params,
+    /// Checked against when a username doesn't exist, so that the request
+    /// costs the same as one with a wrong password. `None` only if Argon2
+    /// can't hash anything, in which case nobody can sign up either.
+    dummy_hash: Option<String>,
+}
+
+impl PasswordHashing {
+    pub fn new(params: Params) -> Self {
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+        let dummy_hash = argon2
+            .hash_password(b"dummy password", &SaltString::generate(&mut OsRng))
+            .map(|hash| hash.to_string())
+            .ok();
+
+        Self {
+            argon2,
+            params,
+            dummy_hash,
+        }
+    }
+
+    /// Hashing is slow on purpose, so it runs on a blocking thread to keep the
+    /// async workers free for other requests.
+    pub async fn hash(&self, password: &str) -> Option<String> {
+        let hashing = self.clone();
+        let password = password.to_owned();
+        spawn_blocking(move || hashing.hash_blocking(&password))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    pub async fn verify(&self, password: &str, hashed_password: &str) -> Option<VerifiedPassword> {
+        let hashing = self.clone();
+        let password = password.to_owned();
+        let hashed_password = hashed_password.to_owned();
+        spawn_blocking(move || hashing.verify_blocking(&password, &hashed_password))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Spends as long as a real verification without checking anything.
+    pub async fn verify_dummy(&self, password: &str) {
+        let hashing = self.clone();
+        let password = password.to_owned();
+        let _ = spawn_blocking(move || {
+            let Some(dummy_hash) = &hashing.dummy_hash else {
+                return;
+            };
+            if let Ok(parsed_hash) = PasswordHash::new(dummy_hash) {
+                let _ = hashing.argon2.verify_password(password.as_bytes(), &parsed_hash);
+            }
+        })
+        .await;
+    }
+
+    fn hash_blocking(&self, password: &str) -> Option<String> {
+        self.argon2
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .ok()
+            .map(|password| password.to_string())
+    }
+
+    fn verify_blocking(&self, password: &str, hashed_password: &str) -> Option<VerifiedPassword> {
+        let parsed_hash = PasswordHash::new(hashed_password).ok()?;
+
+        let (algorithm, verified) = if parsed_hash.algorithm == argon2::ARGON2ID_IDENT {
+            (
+                HashAlgorithm::Argon2id,
+                self.argon2.verify_password(password.as_bytes(), &parsed_hash),
+            )
+        } else {
+            (
+                HashAlgorithm::Pbkdf2,
+                Pbkdf2.verify_password(password.as_bytes(), &parsed_hash),
+            )
+        };
+        verified.ok()?;
+
+        metrics::record_password_verified(algorithm);
+
+        let needs_rehash = algorithm != HashAlgorithm::Argon2id
+            || Params::try_from(&parsed_hash).map_or(true, |params| {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            });
+
+        Some(VerifiedPassword {
+            algorithm,
+            needs_rehash,
+        })
+    }
+}
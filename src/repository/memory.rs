@@ -0,0 +1,925 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use sqlx::migrate::MigrateError;
+use time::{Duration, OffsetDateTime};
+
+use super::{
+    AccessTokenGrant, AccessTokenRecord, AccessTokenStore, AdminBootstrap, AppError, ClientInfo,
+    EmailError, LoginDetails, LoginThrottleStore, PasswordResetStore, Profile, Repository,
+    RoleError, SessionIds, SessionRecord, SessionStore, SessionToken, SignupError, TotpSecrets,
+    TwoFactorStore, UserRecord, UserRole, UserStore, UserSummary,
+};
+use crate::roles::{Permission, ADMIN_ROLE};
+
+/// The same roles the migrations create, as (level, name, permissions).
+const ROLES: [(i32, &str, &[&str]); 4] = [
+    (0, "user", &[]),
+    (1, "admin", &["admin.view", "roles.assign", "metrics.view"]),
+    (2, "moderator", &["admin.view"]),
+    (3, "owner", &["admin.view", "roles.assign", "metrics.view"]),
+];
+
+fn role(level: i32) -> Option<(&'static str, &'static [&'static str])> {
+    ROLES
+        .iter()
+        .find(|(role_level, _, _)| *role_level == level)
+        .map(|(_, name, permissions)| (*name, *permissions))
+}
+
+fn role_level(name: &str) -> Option<i32> {
+    ROLES
+        .iter()
+        .find(|(_, role_name, _)| *role_name == name)
+        .map(|(level, _, _)| *level)
+}
+
+struct StoredUser {
+    id: i32,
+    username: String,
+    email: Option<String>,
+    profile: Option<String>,
+    permission_level: i32,
+    password_hash: String,
+    totp_secret: Option<Vec<u8>>,
+    totp_pending_secret: Option<Vec<u8>>,
+    totp_last_step: Option<i64>,
+    recovery_code_hashes: Vec<String>,
+}
+
+impl StoredUser {
+    fn can_assign_roles(&self) -> bool {
+        role(self.permission_level)
+            .is_some_and(|(_, permissions)| permissions.contains(&Permission::AssignRoles.as_str()))
+    }
+}
+
+struct StoredSession {
+    id: i32,
+    user_id: i32,
+    created_at: OffsetDateTime,
+    last_seen_at: OffsetDateTime,
+    expires_at: OffsetDateTime,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    refresh_token_hash: Option<String>,
+}
+
+impl StoredSession {
+    fn ids(&self) -> SessionIds {
+        SessionIds {
+            session_id: self.id,
+            user_id: self.user_id,
+        }
+    }
+}
+
+struct StoredChallenge {
+    user_id: i32,
+    failed_attempts: i32,
+    expires_at: OffsetDateTime,
+}
+
+struct StoredAccessToken {
+    id: i32,
+    user_id: i32,
+    name: String,
+    token_hash: String,
+    scopes: Vec<String>,
+    created_at: OffsetDateTime,
+    last_used_at: Option<OffsetDateTime>,
+}
+
+struct StoredResetToken {
+    user_id: i32,
+    expires_at: OffsetDateTime,
+}
+
+struct StoredLoginAttempts {
+    failed_attempts: i32,
+    last_failed_at: OffsetDateTime,
+    locked_until: Option<OffsetDateTime>,
+}
+
+#[derive(Default)]
+struct Data {
+    users: Vec<StoredUser>,
+    sessions: HashMap<SessionToken, StoredSession>,
+    login_challenges: HashMap<String, StoredChallenge>,
+    /// Oldest first.
+    access_tokens: Vec<StoredAccessToken>,
+    reset_tokens: HashMap<String, StoredResetToken>,
+    /// Keyed by kind and key.
+    login_attempts: HashMap<(String, String), StoredLoginAttempts>,
+    next_user_id: i32,
+    next_session_id: i32,
+    next_access_token_id: i32,
+}
+
+impl Data {
+    fn user(&self, user_id: i32) -> Option<&StoredUser> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    fn user_mut(&mut self, user_id: i32) -> Option<&mut StoredUser> {
+        self.users.iter_mut().find(|user| user.id == user_id)
+    }
+
+    fn user_by_name(&self, username: &str) -> Option<&StoredUser> {
+        self.users.iter().find(|user| user.username == username)
+    }
+
+    fn email_taken(&self, email: &str, except: Option<i32>) -> bool {
+        self.users
+            .iter()
+            .any(|user| user.email.as_deref() == Some(email) && Some(user.id) != except)
+    }
+
+    fn session_by_refresh_token(&mut self, refresh_token_hash: &str) -> Option<&mut StoredSession> {
+        self.sessions
+            .values_mut()
+            .find(|session| session.refresh_token_hash.as_deref() == Some(refresh_token_hash))
+    }
+
+    fn ensure_not_last_admin(&self, user_id: i32) -> Result<(), RoleError> {
+        let mut admins = self.users.iter().filter(|user| user.can_assign_roles());
+        match (admins.next(), admins.next()) {
+            (Some(admin), None) if admin.id == user_id => Err(RoleError::LastAdmin),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps everything in memory and forgets it on restart. Meant for tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Data>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn create_user(
+        &self,
+        username: &str,
+        email: Option<&str>,
+        password_hash: &str,
+    ) -> Result<i32, AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        if data.user_by_name(username).is_some() {
+            return Err(SignupError::UsernameExists.into());
+        }
+        if email.is_some_and(|email| data.email_taken(email, None)) {
+            return Err(SignupError::EmailExists.into());
+        }
+
+        data.next_user_id += 1;
+        let id = data.next_user_id;
+        data.users.push(StoredUser {
+            id,
+            username: username.to_owned(),
+            email: email.map(String::from),
+            profile: None,
+            permission_level: 0,
+            password_hash: password_hash.to_owned(),
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            recovery_code_hashes: Vec::new(),
+        });
+
+        Ok(id)
+    }
+
+    async fn user_by_id(&self, user_id: i32) -> Result<Option<UserRecord>, AppError> {
+        let data = self.data.lock().unwrap();
+        let Some(user) = data.user(user_id) else {
+            return Ok(None);
+        };
+        let role = role(user.permission_level);
+
+        Ok(Some(UserRecord {
+            id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            permission_level: user.permission_level,
+            role_name: role.map(|(name, _)| name.to_owned()),
+            permissions: role
+                .map(|(_, permissions)| permissions.iter().map(|p| p.to_string()).collect())
+                .unwrap_or_default(),
+        }))
+    }
+
+    async fn user_id(&self, username: &str) -> Result<Option<i32>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.user_by_name(username).map(|user| user.id))
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<UserRecord>, AppError> {
+        let user_id = {
+            let data = self.data.lock().unwrap();
+            data.users
+                .iter()
+                .find(|user| {
+                    user.email.as_deref().map(str::to_lowercase).as_deref() == Some(email)
+                })
+                .map(|user| user.id)
+        };
+
+        match user_id {
+            Some(user_id) => self.user_by_id(user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn login_details(&self, username: &str) -> Result<Option<LoginDetails>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.user_by_name(username).map(|user| LoginDetails {
+            id: user.id,
+            password_hash: user.password_hash.clone(),
+            two_factor_enabled: user.totp_secret.is_some(),
+        }))
+    }
+
+    async fn password_hash(&self, user_id: i32) -> Result<Option<String>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.user(user_id).map(|user| user.password_hash.clone()))
+    }
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            user.password_hash = password_hash.to_owned();
+        }
+        Ok(())
+    }
+
+    async fn set_email(&self, user_id: i32, email: Option<&str>) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        if email.is_some_and(|email| data.email_taken(email, Some(user_id))) {
+            return Err(EmailError::EmailExists.into());
+        }
+
+        if let Some(user) = data.user_mut(user_id) {
+            user.email = email.map(String::from);
+        }
+
+        Ok(())
+    }
+
+    async fn profile(&self, username: &str) -> Result<Option<Profile>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.user_by_name(username).map(|user| Profile {
+            username: user.username.clone(),
+            profile: user.profile.clone(),
+        }))
+    }
+
+    async fn set_profile(&self, user_id: i32, profile: &str) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            user.profile = Some(profile.to_owned());
+        }
+        Ok(())
+    }
+
+    async fn usernames(&self, limit: i64) -> Result<Vec<String>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .users
+            .iter()
+            .take(limit as usize)
+            .map(|user| user.username.clone())
+            .collect())
+    }
+
+    async fn user_roles(&self, limit: i64) -> Result<Vec<UserRole>, AppError> {
+        let data = self.data.lock().unwrap();
+        let mut users: Vec<UserRole> = data
+            .users
+            .iter()
+            .map(|user| UserRole {
+                username: user.username.clone(),
+                role: role(user.permission_level)
+                    .map_or("unknown", |(name, _)| name)
+                    .to_owned(),
+            })
+            .collect();
+
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users.truncate(limit as usize);
+        Ok(users)
+    }
+
+    async fn change_role(
+        &self,
+        username: &str,
+        role_name: &str,
+        from: &[&str],
+    ) -> Result<bool, AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        let Some(user) = data.user_by_name(username) else {
+            return Ok(false);
+        };
+        let user_id = user.id;
+        let current_role = role(user.permission_level).map(|(name, _)| name);
+        let (Some(level), true) = (
+            role_level(role_name),
+            current_role.is_some_and(|name| from.contains(&name)),
+        ) else {
+            return Ok(false);
+        };
+
+        data.ensure_not_last_admin(user_id)?;
+
+        if let Some(user) = data.user_mut(user_id) {
+            user.permission_level = level;
+        }
+
+        Ok(true)
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>, AppError> {
+        let data = self.data.lock().unwrap();
+        let mut users: Vec<UserSummary> = data
+            .users
+            .iter()
+            .map(|user| UserSummary {
+                username: user.username.clone(),
+                email: user.email.clone(),
+                role: role(user.permission_level)
+                    .map_or("unknown", |(name, _)| name)
+                    .to_owned(),
+                sessions: data
+                    .sessions
+                    .values()
+                    .filter(|session| session.user_id == user.id)
+                    .count() as i64,
+            })
+            .collect();
+
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn bootstrap_admin(
+        &self,
+        username: &str,
+        password_hash: Option<&str>,
+    ) -> Result<AdminBootstrap, AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        if data.users.iter().any(StoredUser::can_assign_roles) {
+            return Ok(AdminBootstrap::AdminExists);
+        }
+
+        let level = role_level(ADMIN_ROLE).unwrap_or_default();
+        if let Some(user) = data.users.iter_mut().find(|user| user.username == username) {
+            user.permission_level = level;
+            return Ok(AdminBootstrap::Promoted);
+        }
+
+        let Some(password_hash) = password_hash else {
+            return Ok(AdminBootstrap::NoSuchUser);
+        };
+
+        data.next_user_id += 1;
+        let id = data.next_user_id;
+        data.users.push(StoredUser {
+            id,
+            username: username.to_owned(),
+            email: None,
+            profile: None,
+            permission_level: level,
+            password_hash: password_hash.to_owned(),
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: None,
+            recovery_code_hashes: Vec::new(),
+        });
+
+        Ok(AdminBootstrap::Created)
+    }
+
+    async fn delete_user(&self, user_id: i32) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        data.ensure_not_last_admin(user_id)?;
+
+        data.users.retain(|user| user.id != user_id);
+        data.sessions.retain(|_, session| session.user_id != user_id);
+        data.login_challenges
+            .retain(|_, challenge| challenge.user_id != user_id);
+        data.access_tokens.retain(|token| token.user_id != user_id);
+        data.reset_tokens.retain(|_, token| token.user_id != user_id);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn create_session(
+        &self,
+        session_token: SessionToken,
+        user_id: i32,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<(), AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+
+        data.sessions.retain(|_, session| session.expires_at > now);
+        data.next_session_id += 1;
+        let id = data.next_session_id;
+        data.sessions.insert(
+            session_token,
+            StoredSession {
+                id,
+                user_id,
+                created_at: now,
+                last_seen_at: now,
+                expires_at: now + Duration::seconds(lifetime_seconds),
+                user_agent: client_info.user_agent.clone(),
+                ip_address: client_info.ip_address.clone(),
+                refresh_token_hash: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn refresh_session(
+        &self,
+        session_token: SessionToken,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<bool, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+
+        match data.sessions.get_mut(&session_token) {
+            Some(session) if session.expires_at > now => {
+                session.last_seen_at = now;
+                session.expires_at = now + Duration::seconds(lifetime_seconds);
+                if let Some(user_agent) = &client_info.user_agent {
+                    session.user_agent = Some(user_agent.clone());
+                }
+                if let Some(ip_address) = &client_info.ip_address {
+                    session.ip_address = Some(ip_address.clone());
+                }
+                Ok(true)
+            }
+            _ => {
+                data.sessions.remove(&session_token);
+                Ok(false)
+            }
+        }
+    }
+
+    async fn session_user(&self, session_token: SessionToken) -> Result<Option<i32>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.sessions.get(&session_token).map(|session| session.user_id))
+    }
+
+    async fn delete_session(&self, session_token: SessionToken) -> Result<(), AppError> {
+        self.data.lock().unwrap().sessions.remove(&session_token);
+        Ok(())
+    }
+
+    async fn delete_user_sessions(
+        &self,
+        user_id: i32,
+        keep: Option<SessionToken>,
+    ) -> Result<u64, AppError> {
+        let mut data = self.data.lock().unwrap();
+        let before = data.sessions.len();
+
+        data.sessions
+            .retain(|token, session| session.user_id != user_id || Some(*token) == keep);
+
+        Ok((before - data.sessions.len()) as u64)
+    }
+
+    async fn user_sessions(
+        &self,
+        session_token: SessionToken,
+    ) -> Result<Vec<SessionRecord>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let data = self.data.lock().unwrap();
+        let Some(current) = data.sessions.get(&session_token) else {
+            return Ok(Vec::new());
+        };
+
+        let mut sessions: Vec<SessionRecord> = data
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.user_id == current.user_id && session.expires_at > now)
+            .map(|(token, session)| SessionRecord {
+                id: session.id,
+                created_at: session.created_at,
+                last_seen_at: session.last_seen_at,
+                user_agent: session.user_agent.clone(),
+                ip_address: session.ip_address.clone(),
+                current: *token == session_token,
+            })
+            .collect();
+
+        sessions.sort_by_key(|session| std::cmp::Reverse((session.last_seen_at, session.id)));
+        Ok(sessions)
+    }
+
+    async fn revoke_session(
+        &self,
+        session_token: SessionToken,
+        session_id: i32,
+    ) -> Result<Option<bool>, AppError> {
+        let mut data = self.data.lock().unwrap();
+        let Some(user_id) = data.sessions.get(&session_token).map(|session| session.user_id) else {
+            return Ok(None);
+        };
+        let Some(token) = data
+            .sessions
+            .iter()
+            .find(|(_, session)| session.id == session_id && session.user_id == user_id)
+            .map(|(token, _)| *token)
+        else {
+            return Ok(None);
+        };
+
+        data.sessions.remove(&token);
+        Ok(Some(token == session_token))
+    }
+
+    async fn session_by_id(&self, ids: SessionIds) -> Result<Option<SessionToken>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .sessions
+            .iter()
+            .find(|(_, session)| session.ids() == ids)
+            .map(|(token, _)| *token))
+    }
+
+    async fn set_refresh_token(
+        &self,
+        session_token: SessionToken,
+        refresh_token_hash: &str,
+    ) -> Result<Option<SessionIds>, AppError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.sessions.get_mut(&session_token).map(|session| {
+            session.refresh_token_hash = Some(refresh_token_hash.to_owned());
+            session.ids()
+        }))
+    }
+
+    async fn swap_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<Option<SessionIds>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+
+        match data.session_by_refresh_token(refresh_token_hash) {
+            Some(session) if session.expires_at > now => {
+                session.refresh_token_hash = Some(new_refresh_token_hash.to_owned());
+                session.last_seen_at = now;
+                session.expires_at = now + Duration::seconds(lifetime_seconds);
+                Ok(Some(session.ids()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for MemoryStore {
+    async fn totp_secrets(&self, user_id: i32) -> Result<Option<TotpSecrets>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data.user(user_id).map(|user| TotpSecrets {
+            secret: user.totp_secret.clone(),
+            pending_secret: user.totp_pending_secret.clone(),
+        }))
+    }
+
+    async fn set_pending_totp_secret(&self, user_id: i32, secret: &[u8]) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            if user.totp_secret.is_none() {
+                user.totp_pending_secret = Some(secret.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    async fn enable_totp(&self, user_id: i32, step: i64) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            if let Some(secret) = user.totp_pending_secret.take() {
+                user.totp_secret = Some(secret);
+                user.totp_last_step = Some(step);
+            }
+        }
+        Ok(())
+    }
+
+    async fn disable_totp(&self, user_id: i32) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            user.totp_secret = None;
+            user.totp_pending_secret = None;
+            user.totp_last_step = None;
+            user.recovery_code_hashes.clear();
+        }
+        Ok(())
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AppError> {
+        let mut data = self.data.lock().unwrap();
+        match data.user_mut(user_id) {
+            Some(user) if user.totp_last_step.is_none_or(|last_step| last_step < step) => {
+                user.totp_last_step = Some(step);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_recovery_codes(
+        &self,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(user) = data.user_mut(user_id) {
+            user.recovery_code_hashes = code_hashes.to_vec();
+        }
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AppError> {
+        let mut data = self.data.lock().unwrap();
+        let Some(user) = data.user_mut(user_id) else {
+            return Ok(false);
+        };
+
+        let before = user.recovery_code_hashes.len();
+        user.recovery_code_hashes.retain(|hash| hash != code_hash);
+        Ok(user.recovery_code_hashes.len() < before)
+    }
+
+    async fn recovery_codes_left(&self, user_id: i32) -> Result<i64, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .user(user_id)
+            .map_or(0, |user| user.recovery_code_hashes.len() as i64))
+    }
+
+    async fn create_login_challenge(
+        &self,
+        challenge_hash: &str,
+        user_id: i32,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+
+        data.login_challenges
+            .retain(|_, challenge| challenge.expires_at > now);
+        data.login_challenges.insert(
+            challenge_hash.to_owned(),
+            StoredChallenge {
+                user_id,
+                failed_attempts: 0,
+                expires_at: now + Duration::seconds(lifetime_seconds),
+            },
+        );
+        Ok(())
+    }
+
+    async fn login_challenge_user(&self, challenge_hash: &str) -> Result<Option<i32>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .login_challenges
+            .get(challenge_hash)
+            .filter(|challenge| challenge.expires_at > now)
+            .map(|challenge| challenge.user_id))
+    }
+
+    async fn fail_login_challenge(&self, challenge_hash: &str) -> Result<i32, AppError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .login_challenges
+            .get_mut(challenge_hash)
+            .map_or(0, |challenge| {
+                challenge.failed_attempts += 1;
+                challenge.failed_attempts
+            }))
+    }
+
+    async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<(), AppError> {
+        self.data
+            .lock()
+            .unwrap()
+            .login_challenges
+            .remove(challenge_hash);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AccessTokenStore for MemoryStore {
+    async fn create_access_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        scopes: &[&str],
+    ) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+
+        data.next_access_token_id += 1;
+        let id = data.next_access_token_id;
+        data.access_tokens.push(StoredAccessToken {
+            id,
+            user_id,
+            name: name.to_owned(),
+            token_hash: token_hash.to_owned(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            created_at: OffsetDateTime::now_utc(),
+            last_used_at: None,
+        });
+        Ok(())
+    }
+
+    async fn use_access_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<AccessTokenGrant>, AppError> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .access_tokens
+            .iter_mut()
+            .find(|token| token.token_hash == token_hash)
+            .map(|token| {
+                token.last_used_at = Some(OffsetDateTime::now_utc());
+                AccessTokenGrant {
+                    user_id: token.user_id,
+                    scopes: token.scopes.clone(),
+                }
+            }))
+    }
+
+    async fn access_tokens(&self, user_id: i32) -> Result<Vec<AccessTokenRecord>, AppError> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .access_tokens
+            .iter()
+            .rev()
+            .filter(|token| token.user_id == user_id)
+            .map(|token| AccessTokenRecord {
+                id: token.id,
+                name: token.name.clone(),
+                scopes: token.scopes.clone(),
+                created_at: token.created_at,
+                last_used_at: token.last_used_at,
+            })
+            .collect())
+    }
+
+    async fn delete_access_token(&self, user_id: i32, token_id: i32) -> Result<(), AppError> {
+        self.data
+            .lock()
+            .unwrap()
+            .access_tokens
+            .retain(|token| token.id != token_id || token.user_id != user_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PasswordResetStore for MemoryStore {
+    async fn create_reset_token(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+
+        data.reset_tokens
+            .retain(|_, token| token.user_id != user_id && token.expires_at > now);
+        data.reset_tokens.insert(
+            token_hash.to_owned(),
+            StoredResetToken {
+                user_id,
+                expires_at: now + Duration::seconds(lifetime_seconds),
+            },
+        );
+        Ok(())
+    }
+
+    async fn reset_token_user(&self, token_hash: &str) -> Result<Option<i32>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .reset_tokens
+            .get(token_hash)
+            .filter(|token| token.expires_at > now)
+            .map(|token| token.user_id))
+    }
+
+    async fn redeem_reset_token(&self, token_hash: &str) -> Result<Option<i32>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .reset_tokens
+            .remove(token_hash)
+            .filter(|token| token.expires_at > now)
+            .map(|token| token.user_id))
+    }
+}
+
+#[async_trait]
+impl LoginThrottleStore for MemoryStore {
+    async fn login_lockout(&self, kind: &str, key: &str) -> Result<Option<i64>, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .login_attempts
+            .get(&(kind.to_owned(), key.to_owned()))
+            .and_then(|attempts| attempts.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| (locked_until - now).as_seconds_f64().ceil() as i64))
+    }
+
+    async fn record_login_failure(
+        &self,
+        kind: &str,
+        key: &str,
+        window_seconds: i64,
+    ) -> Result<i32, AppError> {
+        let now = OffsetDateTime::now_utc();
+        let window_start = now - Duration::seconds(window_seconds);
+        let mut data = self.data.lock().unwrap();
+
+        data.login_attempts.retain(|_, attempts| {
+            attempts.last_failed_at >= window_start
+                || attempts.locked_until.is_some_and(|locked_until| locked_until > now)
+        });
+
+        let attempts = data
+            .login_attempts
+            .entry((kind.to_owned(), key.to_owned()))
+            .or_insert(StoredLoginAttempts {
+                failed_attempts: 0,
+                last_failed_at: now,
+                locked_until: None,
+            });
+        if attempts.last_failed_at < window_start {
+            attempts.failed_attempts = 0;
+        }
+        attempts.failed_attempts += 1;
+        attempts.last_failed_at = now;
+
+        Ok(attempts.failed_attempts)
+    }
+
+    async fn lock_logins(&self, kind: &str, key: &str, seconds: i64) -> Result<(), AppError> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(attempts) = data
+            .login_attempts
+            .get_mut(&(kind.to_owned(), key.to_owned()))
+        {
+            attempts.locked_until = Some(OffsetDateTime::now_utc() + Duration::seconds(seconds));
+        }
+        Ok(())
+    }
+
+    async fn clear_login_failures(&self, kind: &str, key: &str) -> Result<(), AppError> {
+        self.data
+            .lock()
+            .unwrap()
+            .login_attempts
+            .remove(&(kind.to_owned(), key.to_owned()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryStore {
+    /// There is no schema to keep up to date.
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        Ok(())
+    }
+}
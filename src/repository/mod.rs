@@ -0,0 +1,381 @@
+//! Everything the site stores. The handlers reach it through the traits that
+//! make up [`Repository`], so the same code runs against Postgres, a single
+//! SQLite file or memory.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sqlx::{
+    migrate::MigrateError,
+    postgres::PgPool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+};
+use time::OffsetDateTime;
+
+pub use crate::{
+    auth::{ClientInfo, SessionToken},
+    errors::{AppError, EmailError, RoleError, SignupError},
+};
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/// A user as stored, with their role's name and permissions. The name is
+/// `None` when their level has no role.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct UserRecord {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub permission_level: i32,
+    pub role_name: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+/// What is needed to check a user's password.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct LoginDetails {
+    pub id: i32,
+    pub password_hash: String,
+    pub two_factor_enabled: bool,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Profile {
+    pub username: String,
+    pub profile: Option<String>,
+}
+
+/// A user as listed by the admin tool.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct UserSummary {
+    pub username: String,
+    pub email: Option<String>,
+    pub role: String,
+    pub sessions: i64,
+}
+
+/// What [`UserStore::bootstrap_admin`] did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdminBootstrap {
+    /// Someone can already assign roles, so nothing was changed.
+    AdminExists,
+    Promoted,
+    Created,
+    /// The user doesn't exist and no password hash was given to create them.
+    NoSuchUser,
+}
+
+#[derive(Clone, Debug, serde::Serialize, sqlx::FromRow)]
+pub struct UserRole {
+    pub username: String,
+    /// "unknown" when the user's level has no role.
+    pub role: String,
+}
+
+/// A session as shown to its user.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct SessionRecord {
+    pub id: i32,
+    pub created_at: OffsetDateTime,
+    pub last_seen_at: OffsetDateTime,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Whether this is the session the list was asked for with.
+    pub current: bool,
+}
+
+/// What an API access token is issued for.
+#[derive(Clone, Copy, Debug, PartialEq, sqlx::FromRow)]
+pub struct SessionIds {
+    pub session_id: i32,
+    pub user_id: i32,
+}
+
+/// A user's TOTP secrets. `pending_secret` is waiting for its first code
+/// before it replaces `secret`.
+#[derive(Clone, Debug, Default, sqlx::FromRow)]
+pub struct TotpSecrets {
+    pub secret: Option<Vec<u8>>,
+    pub pending_secret: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct AccessTokenRecord {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// Who a personal access token acts for and what it may do.
+#[derive(Clone, Debug, PartialEq, sqlx::FromRow)]
+pub struct AccessTokenGrant {
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Stores a new user, returning their id. Fails with
+    /// [`SignupError::UsernameExists`] or [`SignupError::EmailExists`] when
+    /// either is taken.
+    async fn create_user(
+        &self,
+        username: &str,
+        email: Option<&str>,
+        password_hash: &str,
+    ) -> Result<i32, AppError>;
+
+    async fn user_by_id(&self, user_id: i32) -> Result<Option<UserRecord>, AppError>;
+
+    async fn user_id(&self, username: &str) -> Result<Option<i32>, AppError>;
+
+    /// Looks up an address given in lowercase, ignoring the case it was saved
+    /// with.
+    async fn user_by_email(&self, email: &str) -> Result<Option<UserRecord>, AppError>;
+
+    async fn login_details(&self, username: &str) -> Result<Option<LoginDetails>, AppError>;
+
+    async fn password_hash(&self, user_id: i32) -> Result<Option<String>, AppError>;
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), AppError>;
+
+    /// Fails with [`EmailError::EmailExists`] when another user has the address.
+    async fn set_email(&self, user_id: i32, email: Option<&str>) -> Result<(), AppError>;
+
+    async fn profile(&self, username: &str) -> Result<Option<Profile>, AppError>;
+
+    async fn set_profile(&self, user_id: i32, profile: &str) -> Result<(), AppError>;
+
+    async fn usernames(&self, limit: i64) -> Result<Vec<String>, AppError>;
+
+    /// Users with the name of their role, ordered by username.
+    async fn user_roles(&self, limit: i64) -> Result<Vec<UserRole>, AppError>;
+
+    /// Gives a user the named role, but only if their current role is one of
+    /// `from`. Returns whether the role changed. Refuses to demote the last
+    /// user who can assign roles with [`RoleError::LastAdmin`].
+    async fn change_role(&self, username: &str, role: &str, from: &[&str])
+        -> Result<bool, AppError>;
+
+    /// Every user with their role and number of sessions, ordered by username.
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>, AppError>;
+
+    /// Makes the first admin, unless a user who can assign roles already
+    /// exists. An existing user is promoted, otherwise one is created with
+    /// `password_hash`. Two instances starting at once can't both make one.
+    async fn bootstrap_admin(
+        &self,
+        username: &str,
+        password_hash: Option<&str>,
+    ) -> Result<AdminBootstrap, AppError>;
+
+    /// Deletes a user along with everything that belongs to them, unless they
+    /// are the last user who can assign roles.
+    async fn delete_user(&self, user_id: i32) -> Result<(), AppError>;
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Stores a new session, clearing out expired ones while at it.
+    async fn create_session(
+        &self,
+        session_token: SessionToken,
+        user_id: i32,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<(), AppError>;
+
+    /// Extends a session that is still valid and returns whether it was.
+    /// Expired sessions are deleted so that the token can never be used again.
+    async fn refresh_session(
+        &self,
+        session_token: SessionToken,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<bool, AppError>;
+
+    async fn session_user(&self, session_token: SessionToken) -> Result<Option<i32>, AppError>;
+
+    async fn delete_session(&self, session_token: SessionToken) -> Result<(), AppError>;
+
+    /// Deletes every session of a user except `keep`, returning how many were
+    /// deleted.
+    async fn delete_user_sessions(&self, user_id: i32, keep: Option<SessionToken>)
+        -> Result<u64, AppError>;
+
+    /// The valid sessions of the user that `session_token` belongs to, most
+    /// recently used first.
+    async fn user_sessions(&self, session_token: SessionToken)
+        -> Result<Vec<SessionRecord>, AppError>;
+
+    /// Deletes session `session_id` if it belongs to the same user as
+    /// `session_token`. Returns whether it was `session_token` itself, or
+    /// `None` if there was no such session.
+    async fn revoke_session(
+        &self,
+        session_token: SessionToken,
+        session_id: i32,
+    ) -> Result<Option<bool>, AppError>;
+
+    async fn session_by_id(&self, ids: SessionIds) -> Result<Option<SessionToken>, AppError>;
+
+    /// Gives a session a refresh token, replacing any it had before.
+    async fn set_refresh_token(
+        &self,
+        session_token: SessionToken,
+        refresh_token_hash: &str,
+    ) -> Result<Option<SessionIds>, AppError>;
+
+    /// Replaces a refresh token with a new one and extends its session, as
+    /// long as the session is still valid.
+    async fn swap_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<Option<SessionIds>, AppError>;
+}
+
+#[async_trait]
+pub trait TwoFactorStore: Send + Sync {
+    async fn totp_secrets(&self, user_id: i32) -> Result<Option<TotpSecrets>, AppError>;
+
+    /// Stores a secret waiting to be confirmed, unless two-factor
+    /// authentication is already on.
+    async fn set_pending_totp_secret(&self, user_id: i32, secret: &[u8]) -> Result<(), AppError>;
+
+    /// Turns the pending secret on, with `step` as its last used time step.
+    async fn enable_totp(&self, user_id: i32, step: i64) -> Result<(), AppError>;
+
+    /// Turns two-factor authentication off and deletes the recovery codes.
+    async fn disable_totp(&self, user_id: i32) -> Result<(), AppError>;
+
+    /// Marks a time step as used. Returns false if it or a later one already
+    /// was, so that each code only works once.
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AppError>;
+
+    /// Replaces all of a user's recovery codes.
+    async fn set_recovery_codes(&self, user_id: i32, code_hashes: &[String])
+        -> Result<(), AppError>;
+
+    /// Deletes a recovery code, returning whether the user had it.
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AppError>;
+
+    async fn recovery_codes_left(&self, user_id: i32) -> Result<i64, AppError>;
+
+    /// Stores a new login challenge, clearing out expired ones while at it.
+    async fn create_login_challenge(
+        &self,
+        challenge_hash: &str,
+        user_id: i32,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError>;
+
+    /// The user a challenge that hasn't expired is for.
+    async fn login_challenge_user(&self, challenge_hash: &str) -> Result<Option<i32>, AppError>;
+
+    /// Counts a wrong code against a challenge and returns how many there have
+    /// been.
+    async fn fail_login_challenge(&self, challenge_hash: &str) -> Result<i32, AppError>;
+
+    async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<(), AppError>;
+}
+
+#[async_trait]
+pub trait AccessTokenStore: Send + Sync {
+    async fn create_access_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        scopes: &[&str],
+    ) -> Result<(), AppError>;
+
+    /// Looks up a token and records that it was used.
+    async fn use_access_token(&self, token_hash: &str)
+        -> Result<Option<AccessTokenGrant>, AppError>;
+
+    /// A user's tokens, newest first.
+    async fn access_tokens(&self, user_id: i32) -> Result<Vec<AccessTokenRecord>, AppError>;
+
+    async fn delete_access_token(&self, user_id: i32, token_id: i32) -> Result<(), AppError>;
+}
+
+#[async_trait]
+pub trait PasswordResetStore: Send + Sync {
+    /// Stores a reset token, replacing any the user had before and clearing
+    /// out expired ones.
+    async fn create_reset_token(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError>;
+
+    /// The user a reset token that hasn't expired is for.
+    async fn reset_token_user(&self, token_hash: &str) -> Result<Option<i32>, AppError>;
+
+    /// Like [`reset_token_user`](Self::reset_token_user), but also deletes the
+    /// token so it can't be used again.
+    async fn redeem_reset_token(&self, token_hash: &str) -> Result<Option<i32>, AppError>;
+}
+
+/// Failed logins, counted per `kind` of key, such as a username or address.
+#[async_trait]
+pub trait LoginThrottleStore: Send + Sync {
+    /// How many seconds are left on the key's lockout, if it is locked.
+    async fn login_lockout(&self, kind: &str, key: &str) -> Result<Option<i64>, AppError>;
+
+    /// Counts a failure and returns how many there have been. The count starts
+    /// again if the last failure was more than `window_seconds` ago, and keys
+    /// that haven't failed for that long are forgotten while at it.
+    async fn record_login_failure(
+        &self,
+        kind: &str,
+        key: &str,
+        window_seconds: i64,
+    ) -> Result<i32, AppError>;
+
+    async fn lock_logins(&self, kind: &str, key: &str, seconds: i64) -> Result<(), AppError>;
+
+    async fn clear_login_failures(&self, kind: &str, key: &str) -> Result<(), AppError>;
+}
+
+/// Everything the site stores.
+#[async_trait]
+pub trait Repository:
+    UserStore + SessionStore + TwoFactorStore + AccessTokenStore + PasswordResetStore + LoginThrottleStore
+{
+    /// Brings the schema up to date. Run this before serving requests.
+    async fn migrate(&self) -> Result<(), MigrateError>;
+}
+
+pub type Store = Arc<dyn Repository>;
+
+/// Connects to the database at `database_url`, which is a SQLite database for
+/// `sqlite:` URLs and Postgres otherwise. The database isn't migrated yet.
+pub async fn connect(database_url: &str) -> Result<Store, sqlx::Error> {
+    if !database_url.starts_with("sqlite:") {
+        return Ok(Arc::new(PostgresStore::new(PgPool::connect(database_url).await?)));
+    }
+
+    let options = database_url
+        .parse::<SqliteConnectOptions>()?
+        .create_if_missing(true);
+    // Every connection to `:memory:` gets its own database, so keep to one.
+    let max_connections = if database_url.contains(":memory:") { 1 } else { 10 };
+    let database = SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(options)
+        .await?;
+
+    Ok(Arc::new(SqliteStore::new(database)))
+}
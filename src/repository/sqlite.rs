@@ -0,0 +1,983 @@
+use async_trait::async_trait;
+use sqlx::{
+    error::ErrorKind,
+    migrate::{MigrateError, Migrator},
+    Sqlite, SqlitePool, Transaction,
+};
+use tracing::info;
+
+use time::OffsetDateTime;
+
+use super::{
+    AccessTokenGrant, AccessTokenRecord, AccessTokenStore, AdminBootstrap, AppError, ClientInfo,
+    EmailError, LoginDetails, LoginThrottleStore, PasswordResetStore, Profile, Repository,
+    RoleError, SessionIds, SessionRecord, SessionStore, SessionToken, SignupError, TotpSecrets,
+    TwoFactorStore, UserRecord, UserRole, UserStore, UserSummary,
+};
+use crate::roles::{Permission, ADMIN_ROLE};
+
+/// Kept apart from the Postgres migrations, since the two schemas differ in
+/// their types.
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+/// Keeps everything in one SQLite database, which suits small deployments.
+#[derive(Clone)]
+pub struct SqliteStore {
+    database: SqlitePool,
+}
+
+/// SQLite has no arrays, so permissions come back joined by commas.
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: i32,
+    username: String,
+    email: Option<String>,
+    permission_level: i32,
+    role_name: Option<String>,
+    permissions: Option<String>,
+}
+
+impl From<UserRow> for UserRecord {
+    fn from(row: UserRow) -> Self {
+        Self {
+            id: row.id,
+            username: row.username,
+            email: row.email,
+            permission_level: row.permission_level,
+            role_name: row.role_name,
+            permissions: split_commas(row.permissions),
+        }
+    }
+}
+
+fn split_commas(joined: Option<String>) -> Vec<String> {
+    joined
+        .map(|joined| joined.split(',').map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Scopes are joined by commas as well.
+#[derive(sqlx::FromRow)]
+struct AccessTokenRow {
+    id: i32,
+    name: String,
+    scopes: String,
+    created_at: OffsetDateTime,
+    last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<AccessTokenRow> for AccessTokenRecord {
+    fn from(row: AccessTokenRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            scopes: split_commas(Some(row.scopes)),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+impl SqliteStore {
+    /// Expects the database to be migrated with [`Repository::migrate`].
+    pub fn new(database: SqlitePool) -> Self {
+        Self { database }
+    }
+}
+
+/// Fails if `user_id` is the last user who can assign roles. SQLite has no
+/// `FOR UPDATE`, but it only allows one writer, so of two transactions that
+/// both pass this check only one can go on to change anything.
+async fn ensure_not_last_admin(
+    transaction: &mut Transaction<'_, Sqlite>,
+    user_id: i32,
+) -> Result<(), AppError> {
+    const QUERY: &str = "SELECT users.id FROM users
+        JOIN role_permissions ON role_level = permission_level
+        WHERE permission = ?;";
+
+    let admins: Vec<i32> = sqlx::query_scalar(QUERY)
+        .bind(Permission::AssignRoles.as_str())
+        .fetch_all(&mut **transaction)
+        .await?;
+
+    if admins == [user_id] {
+        Err(RoleError::LastAdmin.into())
+    } else {
+        Ok(())
+    }
+}
+
+fn is_unique_violation(error: &sqlx::Error, column: &str) -> bool {
+    matches!(error, sqlx::Error::Database(database)
+        if database.kind() == ErrorKind::UniqueViolation && database.message().ends_with(column))
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn create_user(
+        &self,
+        username: &str,
+        email: Option<&str>,
+        password_hash: &str,
+    ) -> Result<i32, AppError> {
+        const INSERT_USER_QUERY: &str =
+            "INSERT INTO users (username, email, password) VALUES (?, ?, ?) RETURNING id;";
+
+        let fetch_one = sqlx::query_scalar(INSERT_USER_QUERY)
+            .bind(username)
+            .bind(email)
+            .bind(password_hash)
+            .fetch_one(&self.database)
+            .await;
+
+        match fetch_one {
+            Ok(user_id) => Ok(user_id),
+            Err(e) if is_unique_violation(&e, "users.email") => {
+                info!("Sign in error: Email already in use");
+                Err(SignupError::EmailExists.into())
+            }
+            Err(e) if is_unique_violation(&e, "users.username") => {
+                info!("Sign in error: Username already exists");
+                Err(SignupError::UsernameExists.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn user_by_id(&self, user_id: i32) -> Result<Option<UserRecord>, AppError> {
+        const QUERY: &str = "SELECT users.id, username, email, permission_level,
+                roles.name AS role_name,
+                (SELECT group_concat(permission) FROM role_permissions WHERE role_level = permission_level) AS permissions
+            FROM users
+            LEFT JOIN roles ON roles.level = permission_level
+            WHERE users.id = ?;";
+
+        let row: Option<UserRow> = sqlx::query_as(QUERY)
+            .bind(user_id)
+            .fetch_optional(&self.database)
+            .await?;
+
+        Ok(row.map(UserRecord::from))
+    }
+
+    async fn user_id(&self, username: &str) -> Result<Option<i32>, AppError> {
+        const QUERY: &str = "SELECT id FROM users WHERE username = ?;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(username)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<UserRecord>, AppError> {
+        const QUERY: &str = "SELECT id FROM users WHERE lower(email) = ?;";
+
+        let user_id: Option<i32> = sqlx::query_scalar(QUERY)
+            .bind(email)
+            .fetch_optional(&self.database)
+            .await?;
+
+        match user_id {
+            Some(user_id) => self.user_by_id(user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn login_details(&self, username: &str) -> Result<Option<LoginDetails>, AppError> {
+        const QUERY: &str = "SELECT id, password AS password_hash,
+                totp_secret IS NOT NULL AS two_factor_enabled
+            FROM users
+            WHERE username = ?;";
+
+        sqlx::query_as(QUERY)
+            .bind(username)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn password_hash(&self, user_id: i32) -> Result<Option<String>, AppError> {
+        const QUERY: &str = "SELECT password FROM users WHERE id = ?;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(user_id)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn set_password_hash(&self, user_id: i32, password_hash: &str) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE users SET password = ? WHERE id = ?;";
+
+        sqlx::query(QUERY)
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_email(&self, user_id: i32, email: Option<&str>) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE users SET email = ? WHERE id = ?;";
+
+        let result = sqlx::query(QUERY)
+            .bind(email)
+            .bind(user_id)
+            .execute(&self.database)
+            .await;
+
+        if let Err(error) = &result {
+            if is_unique_violation(error, "users.email") {
+                return Err(EmailError::EmailExists.into());
+            }
+        }
+        result?;
+
+        Ok(())
+    }
+
+    async fn profile(&self, username: &str) -> Result<Option<Profile>, AppError> {
+        const QUERY: &str = "SELECT username, profile FROM users WHERE username = ?;";
+
+        sqlx::query_as(QUERY)
+            .bind(username)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn set_profile(&self, user_id: i32, profile: &str) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE users SET profile = ? WHERE id = ?;";
+
+        sqlx::query(QUERY)
+            .bind(profile)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn usernames(&self, limit: i64) -> Result<Vec<String>, AppError> {
+        const QUERY: &str = "SELECT username FROM users LIMIT ?;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(limit)
+            .fetch_all(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn user_roles(&self, limit: i64) -> Result<Vec<UserRole>, AppError> {
+        const QUERY: &str = "SELECT username, COALESCE(roles.name, 'unknown') AS role
+            FROM users
+            LEFT JOIN roles ON roles.level = users.permission_level
+            ORDER BY username
+            LIMIT ?;";
+
+        sqlx::query_as(QUERY)
+            .bind(limit)
+            .fetch_all(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn change_role(
+        &self,
+        username: &str,
+        role: &str,
+        from: &[&str],
+    ) -> Result<bool, AppError> {
+        const USER_QUERY: &str = "SELECT id, (SELECT name FROM roles WHERE level = permission_level)
+            FROM users
+            WHERE username = ?;";
+        const UPDATE_QUERY: &str = "UPDATE users SET permission_level = (SELECT level FROM roles WHERE name = ?)
+            WHERE id = ?;";
+
+        let mut transaction = self.database.begin().await?;
+
+        let user: Option<(i32, Option<String>)> = sqlx::query_as(USER_QUERY)
+            .bind(username)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        // SQLite can't bind a list, so the current role is checked here.
+        let Some((user_id, Some(current_role))) = user else {
+            return Ok(false);
+        };
+        if !from.contains(&current_role.as_str()) {
+            return Ok(false);
+        }
+
+        ensure_not_last_admin(&mut transaction, user_id).await?;
+
+        sqlx::query(UPDATE_QUERY)
+            .bind(role)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn user_summaries(&self) -> Result<Vec<UserSummary>, AppError> {
+        const QUERY: &str = "SELECT username, email, COALESCE(roles.name, 'unknown') AS role,
+                (SELECT count(*) FROM sessions WHERE user_id = users.id) AS sessions
+            FROM users
+            LEFT JOIN roles ON roles.level = users.permission_level
+            ORDER BY username;";
+
+        sqlx::query_as(QUERY)
+            .fetch_all(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    /// SQLite only allows one writer, so the check and the change can't be
+    /// split by another instance doing the same.
+    async fn bootstrap_admin(
+        &self,
+        username: &str,
+        password_hash: Option<&str>,
+    ) -> Result<AdminBootstrap, AppError> {
+        const ADMINS_QUERY: &str = "SELECT EXISTS (SELECT 1 FROM users
+            JOIN role_permissions ON role_level = permission_level
+            WHERE permission = ?);";
+        const PROMOTE_QUERY: &str = "UPDATE users SET permission_level = (SELECT level FROM roles WHERE name = ?)
+            WHERE username = ?;";
+        const INSERT_QUERY: &str = "INSERT INTO users (username, password, permission_level)
+            VALUES (?, ?, (SELECT level FROM roles WHERE name = ?));";
+
+        let mut transaction = self.database.begin().await?;
+
+        let admin_exists: bool = sqlx::query_scalar(ADMINS_QUERY)
+            .bind(Permission::AssignRoles.as_str())
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        if admin_exists {
+            return Ok(AdminBootstrap::AdminExists);
+        }
+
+        let promoted = sqlx::query(PROMOTE_QUERY)
+            .bind(ADMIN_ROLE)
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected()
+            > 0;
+
+        let outcome = if promoted {
+            AdminBootstrap::Promoted
+        } else if let Some(password_hash) = password_hash {
+            sqlx::query(INSERT_QUERY)
+                .bind(username)
+                .bind(password_hash)
+                .bind(ADMIN_ROLE)
+                .execute(&mut *transaction)
+                .await?;
+            AdminBootstrap::Created
+        } else {
+            return Ok(AdminBootstrap::NoSuchUser);
+        };
+
+        transaction.commit().await?;
+
+        Ok(outcome)
+    }
+
+    async fn delete_user(&self, user_id: i32) -> Result<(), AppError> {
+        const DELETE_QUERY: &str = "DELETE FROM users WHERE id = ?;";
+
+        let mut transaction = self.database.begin().await?;
+        ensure_not_last_admin(&mut transaction, user_id).await?;
+
+        sqlx::query(DELETE_QUERY)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn create_session(
+        &self,
+        session_token: SessionToken,
+        user_id: i32,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<(), AppError> {
+        const PURGE_EXPIRED_QUERY: &str = "DELETE FROM sessions WHERE expires_at <= unixepoch();";
+        const INSERT_TOKEN_QUERY: &str =
+            "INSERT INTO sessions (session_token, user_id, expires_at, user_agent, ip_address)
+            VALUES (?, ?, unixepoch() + ?, ?, ?);";
+
+        sqlx::query(PURGE_EXPIRED_QUERY)
+            .execute(&self.database)
+            .await?;
+
+        sqlx::query(INSERT_TOKEN_QUERY)
+            .bind(session_token.into_database_value())
+            .bind(user_id)
+            .bind(lifetime_seconds)
+            .bind(&client_info.user_agent)
+            .bind(&client_info.ip_address)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn refresh_session(
+        &self,
+        session_token: SessionToken,
+        lifetime_seconds: i64,
+        client_info: &ClientInfo,
+    ) -> Result<bool, AppError> {
+        const REFRESH_QUERY: &str = "UPDATE sessions
+            SET last_seen_at = unixepoch(), expires_at = unixepoch() + ?,
+                user_agent = COALESCE(?, user_agent), ip_address = COALESCE(?, ip_address)
+            WHERE session_token = ? AND expires_at > unixepoch();";
+
+        let refreshed = sqlx::query(REFRESH_QUERY)
+            .bind(lifetime_seconds)
+            .bind(&client_info.user_agent)
+            .bind(&client_info.ip_address)
+            .bind(session_token.into_database_value())
+            .execute(&self.database)
+            .await?
+            .rows_affected()
+            > 0;
+
+        if !refreshed {
+            self.delete_session(session_token).await?;
+        }
+
+        Ok(refreshed)
+    }
+
+    async fn session_user(&self, session_token: SessionToken) -> Result<Option<i32>, AppError> {
+        const QUERY: &str = "SELECT user_id FROM sessions WHERE session_token = ?;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(session_token.into_database_value())
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn delete_session(&self, session_token: SessionToken) -> Result<(), AppError> {
+        const DELETE_QUERY: &str = "DELETE FROM sessions WHERE session_token = ?;";
+
+        sqlx::query(DELETE_QUERY)
+            .bind(session_token.into_database_value())
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_user_sessions(
+        &self,
+        user_id: i32,
+        keep: Option<SessionToken>,
+    ) -> Result<u64, AppError> {
+        const DELETE_QUERY: &str =
+            "DELETE FROM sessions WHERE user_id = ? AND session_token IS NOT ?;";
+
+        let result = sqlx::query(DELETE_QUERY)
+            .bind(user_id)
+            .bind(keep.map(SessionToken::into_database_value))
+            .execute(&self.database)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn user_sessions(
+        &self,
+        session_token: SessionToken,
+    ) -> Result<Vec<SessionRecord>, AppError> {
+        const QUERY: &str = "SELECT id, created_at, last_seen_at, user_agent, ip_address,
+                session_token = ?1 AS current
+            FROM sessions
+            WHERE user_id = (SELECT user_id FROM sessions WHERE session_token = ?1)
+                AND expires_at > unixepoch()
+            ORDER BY last_seen_at DESC, id DESC;";
+
+        sqlx::query_as(QUERY)
+            .bind(session_token.into_database_value())
+            .fetch_all(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn revoke_session(
+        &self,
+        session_token: SessionToken,
+        session_id: i32,
+    ) -> Result<Option<bool>, AppError> {
+        const QUERY: &str = "DELETE FROM sessions
+            WHERE id = ?1 AND user_id = (SELECT user_id FROM sessions WHERE session_token = ?2)
+            RETURNING session_token = ?2;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(session_id)
+            .bind(session_token.into_database_value())
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn session_by_id(&self, ids: SessionIds) -> Result<Option<SessionToken>, AppError> {
+        const QUERY: &str = "SELECT session_token FROM sessions WHERE id = ? AND user_id = ?;";
+
+        let session_token: Option<Vec<u8>> = sqlx::query_scalar(QUERY)
+            .bind(ids.session_id)
+            .bind(ids.user_id)
+            .fetch_optional(&self.database)
+            .await?;
+
+        Ok(session_token.and_then(|value| SessionToken::from_database_value(&value)))
+    }
+
+    async fn set_refresh_token(
+        &self,
+        session_token: SessionToken,
+        refresh_token_hash: &str,
+    ) -> Result<Option<SessionIds>, AppError> {
+        const QUERY: &str = "UPDATE sessions SET refresh_token_hash = ?
+            WHERE session_token = ?
+            RETURNING id AS session_id, user_id;";
+
+        sqlx::query_as(QUERY)
+            .bind(refresh_token_hash)
+            .bind(session_token.into_database_value())
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn swap_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<Option<SessionIds>, AppError> {
+        const QUERY: &str = "UPDATE sessions
+            SET refresh_token_hash = ?, last_seen_at = unixepoch(), expires_at = unixepoch() + ?
+            WHERE refresh_token_hash = ? AND expires_at > unixepoch()
+            RETURNING id AS session_id, user_id;";
+
+        sqlx::query_as(QUERY)
+            .bind(new_refresh_token_hash)
+            .bind(lifetime_seconds)
+            .bind(refresh_token_hash)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+}
+
+#[async_trait]
+impl TwoFactorStore for SqliteStore {
+    async fn totp_secrets(&self, user_id: i32) -> Result<Option<TotpSecrets>, AppError> {
+        const QUERY: &str = "SELECT totp_secret AS secret, totp_pending_secret AS pending_secret
+            FROM users
+            WHERE id = ?;";
+
+        sqlx::query_as(QUERY)
+            .bind(user_id)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn set_pending_totp_secret(&self, user_id: i32, secret: &[u8]) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE users SET totp_pending_secret = ?
+            WHERE id = ? AND totp_secret IS NULL;";
+
+        sqlx::query(QUERY)
+            .bind(secret)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn enable_totp(&self, user_id: i32, step: i64) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE users
+            SET totp_secret = totp_pending_secret, totp_pending_secret = NULL, totp_last_step = ?
+            WHERE id = ? AND totp_pending_secret IS NOT NULL;";
+
+        sqlx::query(QUERY)
+            .bind(step)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn disable_totp(&self, user_id: i32) -> Result<(), AppError> {
+        const DISABLE_QUERY: &str = "UPDATE users
+            SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL
+            WHERE id = ?;";
+        const DELETE_RECOVERY_CODES_QUERY: &str = "DELETE FROM recovery_codes WHERE user_id = ?;";
+
+        let mut transaction = self.database.begin().await?;
+
+        sqlx::query(DISABLE_QUERY)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query(DELETE_RECOVERY_CODES_QUERY)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn use_totp_step(&self, user_id: i32, step: i64) -> Result<bool, AppError> {
+        const QUERY: &str = "UPDATE users SET totp_last_step = ?2
+            WHERE id = ?1 AND (totp_last_step IS NULL OR totp_last_step < ?2);";
+
+        let result = sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(step)
+            .execute(&self.database)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_recovery_codes(
+        &self,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> Result<(), AppError> {
+        const DELETE_QUERY: &str = "DELETE FROM recovery_codes WHERE user_id = ?;";
+        const INSERT_QUERY: &str = "INSERT INTO recovery_codes (code_hash, user_id) VALUES (?, ?);";
+
+        let mut transaction = self.database.begin().await?;
+
+        sqlx::query(DELETE_QUERY)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        for code_hash in code_hashes {
+            sqlx::query(INSERT_QUERY)
+                .bind(code_hash)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, AppError> {
+        const QUERY: &str = "DELETE FROM recovery_codes WHERE user_id = ? AND code_hash = ?;";
+
+        let result = sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&self.database)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn recovery_codes_left(&self, user_id: i32) -> Result<i64, AppError> {
+        const QUERY: &str = "SELECT count(*) FROM recovery_codes WHERE user_id = ?;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(user_id)
+            .fetch_one(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn create_login_challenge(
+        &self,
+        challenge_hash: &str,
+        user_id: i32,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError> {
+        const PURGE_EXPIRED_QUERY: &str =
+            "DELETE FROM login_challenges WHERE expires_at <= unixepoch();";
+        const INSERT_QUERY: &str = "INSERT INTO login_challenges (challenge_hash, user_id, expires_at)
+            VALUES (?, ?, unixepoch() + ?);";
+
+        sqlx::query(PURGE_EXPIRED_QUERY)
+            .execute(&self.database)
+            .await?;
+
+        sqlx::query(INSERT_QUERY)
+            .bind(challenge_hash)
+            .bind(user_id)
+            .bind(lifetime_seconds)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn login_challenge_user(&self, challenge_hash: &str) -> Result<Option<i32>, AppError> {
+        const QUERY: &str = "SELECT user_id FROM login_challenges
+            WHERE challenge_hash = ? AND expires_at > unixepoch();";
+
+        sqlx::query_scalar(QUERY)
+            .bind(challenge_hash)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn fail_login_challenge(&self, challenge_hash: &str) -> Result<i32, AppError> {
+        const QUERY: &str = "UPDATE login_challenges
+            SET failed_attempts = failed_attempts + 1
+            WHERE challenge_hash = ?
+            RETURNING failed_attempts;";
+
+        let failed_attempts: Option<i32> = sqlx::query_scalar(QUERY)
+            .bind(challenge_hash)
+            .fetch_optional(&self.database)
+            .await?;
+
+        Ok(failed_attempts.unwrap_or_default())
+    }
+
+    async fn delete_login_challenge(&self, challenge_hash: &str) -> Result<(), AppError> {
+        const QUERY: &str = "DELETE FROM login_challenges WHERE challenge_hash = ?;";
+
+        sqlx::query(QUERY)
+            .bind(challenge_hash)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AccessTokenStore for SqliteStore {
+    async fn create_access_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        scopes: &[&str],
+    ) -> Result<(), AppError> {
+        const QUERY: &str =
+            "INSERT INTO access_tokens (user_id, name, token_hash, scopes) VALUES (?, ?, ?, ?);";
+
+        sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(name)
+            .bind(token_hash)
+            .bind(scopes.join(","))
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn use_access_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<AccessTokenGrant>, AppError> {
+        const QUERY: &str = "UPDATE access_tokens SET last_used_at = unixepoch()
+            WHERE token_hash = ?
+            RETURNING user_id, scopes;";
+
+        let grant: Option<(i32, String)> = sqlx::query_as(QUERY)
+            .bind(token_hash)
+            .fetch_optional(&self.database)
+            .await?;
+
+        Ok(grant.map(|(user_id, scopes)| AccessTokenGrant {
+            user_id,
+            scopes: split_commas(Some(scopes)),
+        }))
+    }
+
+    async fn access_tokens(&self, user_id: i32) -> Result<Vec<AccessTokenRecord>, AppError> {
+        const QUERY: &str = "SELECT id, name, scopes, created_at, last_used_at
+            FROM access_tokens
+            WHERE user_id = ?
+            ORDER BY created_at DESC, id DESC;";
+
+        let rows: Vec<AccessTokenRow> = sqlx::query_as(QUERY)
+            .bind(user_id)
+            .fetch_all(&self.database)
+            .await?;
+
+        Ok(rows.into_iter().map(AccessTokenRecord::from).collect())
+    }
+
+    async fn delete_access_token(&self, user_id: i32, token_id: i32) -> Result<(), AppError> {
+        const QUERY: &str = "DELETE FROM access_tokens WHERE id = ? AND user_id = ?;";
+
+        sqlx::query(QUERY)
+            .bind(token_id)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PasswordResetStore for SqliteStore {
+    async fn create_reset_token(
+        &self,
+        user_id: i32,
+        token_hash: &str,
+        lifetime_seconds: i64,
+    ) -> Result<(), AppError> {
+        const DELETE_QUERY: &str =
+            "DELETE FROM password_reset_tokens WHERE user_id = ? OR expires_at <= unixepoch();";
+        const INSERT_QUERY: &str = "INSERT INTO password_reset_tokens (token_hash, user_id, expires_at)
+            VALUES (?, ?, unixepoch() + ?);";
+
+        sqlx::query(DELETE_QUERY)
+            .bind(user_id)
+            .execute(&self.database)
+            .await?;
+
+        sqlx::query(INSERT_QUERY)
+            .bind(token_hash)
+            .bind(user_id)
+            .bind(lifetime_seconds)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reset_token_user(&self, token_hash: &str) -> Result<Option<i32>, AppError> {
+        const QUERY: &str = "SELECT user_id FROM password_reset_tokens
+            WHERE token_hash = ? AND expires_at > unixepoch();";
+
+        sqlx::query_scalar(QUERY)
+            .bind(token_hash)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn redeem_reset_token(&self, token_hash: &str) -> Result<Option<i32>, AppError> {
+        const QUERY: &str = "DELETE FROM password_reset_tokens
+            WHERE token_hash = ? AND expires_at > unixepoch()
+            RETURNING user_id;";
+
+        sqlx::query_scalar(QUERY)
+            .bind(token_hash)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+}
+
+#[async_trait]
+impl LoginThrottleStore for SqliteStore {
+    async fn login_lockout(&self, kind: &str, key: &str) -> Result<Option<i64>, AppError> {
+        const QUERY: &str = "SELECT locked_until - unixepoch()
+            FROM login_attempts
+            WHERE kind = ? AND key = ? AND locked_until > unixepoch();";
+
+        sqlx::query_scalar(QUERY)
+            .bind(kind)
+            .bind(key)
+            .fetch_optional(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn record_login_failure(
+        &self,
+        kind: &str,
+        key: &str,
+        window_seconds: i64,
+    ) -> Result<i32, AppError> {
+        const PURGE_QUERY: &str = "DELETE FROM login_attempts
+            WHERE last_failed_at < unixepoch() - ?
+                AND (locked_until IS NULL OR locked_until <= unixepoch());";
+        const RECORD_QUERY: &str = "INSERT INTO login_attempts (kind, key, failed_attempts)
+            VALUES (?1, ?2, 1)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                failed_attempts = CASE
+                    WHEN login_attempts.last_failed_at < unixepoch() - ?3 THEN 1
+                    ELSE login_attempts.failed_attempts + 1
+                END,
+                last_failed_at = unixepoch()
+            RETURNING failed_attempts;";
+
+        sqlx::query(PURGE_QUERY)
+            .bind(window_seconds)
+            .execute(&self.database)
+            .await?;
+
+        sqlx::query_scalar(RECORD_QUERY)
+            .bind(kind)
+            .bind(key)
+            .bind(window_seconds)
+            .fetch_one(&self.database)
+            .await
+            .map_err(AppError::from)
+    }
+
+    async fn lock_logins(&self, kind: &str, key: &str, seconds: i64) -> Result<(), AppError> {
+        const QUERY: &str = "UPDATE login_attempts SET locked_until = unixepoch() + ?
+            WHERE kind = ? AND key = ?;";
+
+        sqlx::query(QUERY)
+            .bind(seconds)
+            .bind(kind)
+            .bind(key)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn clear_login_failures(&self, kind: &str, key: &str) -> Result<(), AppError> {
+        const QUERY: &str = "DELETE FROM login_attempts WHERE kind = ? AND key = ?;";
+
+        sqlx::query(QUERY)
+            .bind(kind)
+            .bind(key)
+            .execute(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteStore {
+    /// Brings the database up to the latest SQLite migration.
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        MIGRATOR.run(&self.database).await
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension, Form,
+};
+use tracing::{error, info};
+
+use crate::{
+    auth::{update_password, AuthState, ClientInfo},
+    config::Config,
+    errors::{AppError, PasswordResetError},
+    flash::Flashes,
+    mail::Mail,
+    password::PasswordHashing,
+    repository::Store,
+    throttle::{record_reset_request, reset_lockout},
+    utils::{page_context, random_hex},
+    validation::{check_password, form_context, normalize_email, FieldErrors},
+    Mailer, Random, Templates,
+};
+
+/// Reset links stop working after this many seconds.
+const RESET_TOKEN_LIFETIME_SECONDS: i64 = 60 * 30;
+
+/// Only a digest of each token is stored, so a leaked database can't be used
+/// to reset passwords.
+fn hash_reset_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+async fn create_reset_token(
+    store: &Store,
+    random: Random,
+    user_id: i32,
+) -> Result<String, AppError> {
+    let token = random_hex(random, 32);
+
+    store
+        .create_reset_token(
+            user_id,
+            &hash_reset_token(&token),
+            RESET_TOKEN_LIFETIME_SECONDS,
+        )
+        .await?;
+
+    Ok(token)
+}
+
+pub(crate) async fn get_forgot_password(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Html(templates.render("forgot_password", &page_context(&current_user, &flashes))?))
+}
+
+/// Mails a reset link to the user with `email`, if there is one.
+async fn send_reset_email(
+    store: &Store,
+    random: Random,
+    mailer: &Mailer,
+    config: &Config,
+    email: String,
+) -> Result<(), AppError> {
+    let Some(user) = store.user_by_email(&email).await? else {
+        return Ok(());
+    };
+
+    let username = user.username;
+    let token = create_reset_token(store, random, user.id).await?;
+    let mail = Mail {
+        to: email,
+        subject: "Reset your password".to_owned(),
+        body: format!(
+            "Hi {},\n\nUse the link below to choose a new password. It expires in {} minutes.\n\n{}/reset-password?token={}\n\nIf you didn't ask for this, you can ignore this email.",
+            username,
+            RESET_TOKEN_LIFETIME_SECONDS / 60,
+            config.public_url.trim_end_matches('/'),
+            token
+        ),
+    };
+
+    match mailer.send(mail).await {
+        Ok(()) => info!("Sent password reset email to user '{}'", username),
+        Err(e) => error!("Password reset email for '{}' failed: {}", username, e),
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post_forgot_password(
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(mailer): Extension<Mailer>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client_info): Extension<ClientInfo>,
+    Form(ForgotPasswordForm { email }): Form<ForgotPasswordForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(email) = normalize_email(&email) {
+        let ip_address = client_info.ip_address.as_deref();
+        if let Some(retry_after) = reset_lockout(&store, &email, ip_address).await? {
+            return Err(PasswordResetError::TooManyRequests(retry_after).into());
+        }
+        record_reset_request(&store, &email, ip_address).await?;
+
+        // The response is the same whether or not the address is known, and
+        // the email is sent after it, so neither what this page says nor how
+        // long it takes shows who has an account.
+        tokio::spawn(async move {
+            if let Err(e) = send_reset_email(&store, random, &mailer, &config, email).await {
+                error!("Password reset request failed: {}", e);
+            }
+        });
+    }
+
+    flashes.success(
+        "If an account uses that email address, we've sent it a link to reset the password",
+    );
+    Ok(Redirect::to("/login"))
+}
+
+pub(crate) async fn get_reset_password(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+    Query(query): Query<ResetPasswordQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    if store
+        .reset_token_user(&hash_reset_token(&query.token))
+        .await?
+        .is_none()
+    {
+        return Err(PasswordResetError::InvalidToken.into());
+    }
+
+    let context = form_context(&current_user, &flashes, &query, &FieldErrors::default());
+    Ok(Html(templates.render("reset_password", &context)?))
+}
+
+/// A new password that isn't accepted shows the form again, keeping the token
+/// so the link still works for another try.
+pub(crate) async fn post_reset_password(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(templates): Extension<Templates>,
+    Form(form): Form<ResetPasswordForm>,
+) -> Result<Response, AppError> {
+    let mut errors = FieldErrors::default();
+    errors.check("password", check_password(&form.password));
+    if form.password != form.confirm_password {
+        errors.add("confirm_password", PasswordResetError::PasswordsDoNotMatch.to_string());
+    }
+
+    if !errors.is_empty() {
+        let context = form_context(&current_user, &flashes, &form, &errors);
+        let page = templates.render("reset_password", &context)?;
+        return Ok((StatusCode::BAD_REQUEST, Html(page)).into_response());
+    }
+
+    let Some(user_id) = store.redeem_reset_token(&hash_reset_token(&form.token)).await? else {
+        return Err(PasswordResetError::InvalidToken.into());
+    };
+
+    if !update_password(&store, &hashing, user_id, &form.password).await? {
+        return Err(PasswordResetError::InvalidPassword.into());
+    }
+
+    store.delete_user_sessions(user_id, None).await?;
+    flashes.success("Your password has been reset, you can now log in with it");
+
+    Ok(Redirect::to("/login").into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ForgotPasswordForm {
+    email: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ResetPasswordQuery {
+    token: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ResetPasswordForm {
+    token: String,
+    #[serde(skip_serializing)]
+    password: String,
+    #[serde(skip_serializing)]
+    confirm_password: String,
+}
@@ -0,0 +1,82 @@
+use std::str::FromStr;
+
+use tracing::warn;
+
+/// Something a role allows its users to do. Roles and the permissions they
+/// grant are stored in the `roles` and `role_permissions` tables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Permission {
+    /// See the administration page.
+    ViewAdmin,
+    /// Change which role other users have.
+    AssignRoles,
+    ViewMetrics,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 3] = [
+        Permission::ViewAdmin,
+        Permission::AssignRoles,
+        Permission::ViewMetrics,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ViewAdmin => "admin.view",
+            Permission::AssignRoles => "roles.assign",
+            Permission::ViewMetrics => "metrics.view",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == s)
+            .ok_or(())
+    }
+}
+
+pub(crate) const USER_ROLE: &str = "user";
+pub(crate) const MODERATOR_ROLE: &str = "moderator";
+pub(crate) const ADMIN_ROLE: &str = "admin";
+
+#[derive(Clone, Debug)]
+pub(crate) struct Role {
+    pub name: String,
+    permissions: Vec<Permission>,
+}
+
+impl Role {
+    /// Builds a role from its database row. A level missing from the `roles`
+    /// table, or a permission this version doesn't know, grants nothing.
+    pub fn from_row(level: i32, name: Option<String>, permissions: Vec<String>) -> Self {
+        let Some(name) = name else {
+            warn!("Unknown permission level {}, treating it as having no permissions", level);
+            return Self {
+                name: "unknown".to_owned(),
+                permissions: Vec::new(),
+            };
+        };
+
+        let permissions = permissions
+            .iter()
+            .filter_map(|permission| match permission.parse() {
+                Ok(permission) => Some(permission),
+                Err(()) => {
+                    warn!("Role '{}' has unknown permission '{}'", name, permission);
+                    None
+                }
+            })
+            .collect();
+
+        Self { name, permissions }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
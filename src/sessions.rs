@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse, Redirect},
+    Extension,
+};
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::{
+    auth::{AuthState, SessionToken},
+    config::Config,
+    errors::{AppError, NotLoggedIn},
+    flash::Flashes,
+    repository::Store,
+    utils::{logout_response, page_context},
+    Templates,
+};
+
+#[derive(serde::Serialize)]
+struct SessionInfo {
+    id: i32,
+    created_at: String,
+    last_seen_at: String,
+    user_agent: String,
+    ip_address: String,
+    current: bool,
+}
+
+/// Shows a time as, for example, `2024-01-31 09:05 UTC`.
+pub(crate) fn format_time(time: OffsetDateTime) -> String {
+    let time = time.to_offset(UtcOffset::UTC);
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+async fn get_sessions(
+    store: &Store,
+    session_token: SessionToken,
+) -> Result<Vec<SessionInfo>, AppError> {
+    let sessions = store.user_sessions(session_token).await?;
+
+    Ok(sessions
+        .into_iter()
+        .map(|session| SessionInfo {
+            id: session.id,
+            created_at: format_time(session.created_at),
+            last_seen_at: format_time(session.last_seen_at),
+            user_agent: session.user_agent.unwrap_or_else(|| "Unknown device".to_owned()),
+            ip_address: session.ip_address.unwrap_or_else(|| "Unknown address".to_owned()),
+            current: session.current,
+        })
+        .collect())
+}
+
+pub(crate) async fn sessions(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(session_token) = current_user.session_token() else {
+        return Err(NotLoggedIn.into());
+    };
+
+    let sessions = get_sessions(&store, session_token).await?;
+
+    let mut context = page_context(&current_user, &flashes);
+    context.insert("sessions", &sessions);
+    Ok(Html(templates.render("sessions", &context)?))
+}
+
+pub(crate) async fn revoke_session(
+    Path(id): Path<i32>,
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(session_token) = current_user.session_token() else {
+        return Err(NotLoggedIn.into());
+    };
+
+    match store.revoke_session(session_token, id).await? {
+        Some(true) => Ok(logout_response(&config.cookies).await.into_response()),
+        Some(false) => {
+            flashes.success("The session has been logged out");
+            Ok(Redirect::to("/me/sessions").into_response())
+        }
+        None => Ok(Redirect::to("/me/sessions").into_response()),
+    }
+}
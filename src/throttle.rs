@@ -0,0 +1,125 @@
+use tracing::warn;
+
+use crate::{errors::AppError, repository::Store};
+
+const USERNAME_KIND: &str = "username";
+const IP_ADDRESS_KIND: &str = "ip";
+const RESET_EMAIL_KIND: &str = "reset_email";
+const RESET_IP_ADDRESS_KIND: &str = "reset_ip";
+
+/// Failures allowed for one username before it gets locked.
+const USERNAME_FREE_ATTEMPTS: i32 = 5;
+/// Failures allowed from one address before it gets locked. This is higher
+/// than for usernames since many users can share an address.
+const IP_ADDRESS_FREE_ATTEMPTS: i32 = 20;
+/// Password reset emails one address can be sent before further requests for
+/// it are refused, so nobody can flood an inbox through the site.
+const RESET_EMAIL_FREE_REQUESTS: i32 = 3;
+/// Password reset requests allowed from one address, whichever emails they
+/// are for.
+const RESET_IP_ADDRESS_FREE_REQUESTS: i32 = 10;
+/// The first lockout lasts this long and doubles with every further failure.
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+/// Failures older than this are forgotten.
+const ATTEMPT_WINDOW_SECONDS: i64 = 60 * 60 * 24;
+
+fn lockout_seconds(failed_attempts: i32, free_attempts: i32) -> Option<i64> {
+    let doublings = failed_attempts - free_attempts;
+    (doublings >= 0)
+        .then(|| (BASE_LOCKOUT_SECONDS << doublings.min(16)).min(MAX_LOCKOUT_SECONDS))
+}
+
+/// Returns how many seconds are left before the username or address may try
+/// to log in again, if either is currently locked.
+pub(crate) async fn login_lockout(
+    store: &Store,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<i64>, AppError> {
+    let username_lockout = store.login_lockout(USERNAME_KIND, username).await?;
+    let ip_address_lockout = match ip_address {
+        Some(ip_address) => store.login_lockout(IP_ADDRESS_KIND, ip_address).await?,
+        None => None,
+    };
+
+    Ok(username_lockout.max(ip_address_lockout))
+}
+
+async fn record_failure(
+    store: &Store,
+    kind: &str,
+    key: &str,
+    free_attempts: i32,
+) -> Result<(), AppError> {
+    let failed_attempts = store
+        .record_login_failure(kind, key, ATTEMPT_WINDOW_SECONDS)
+        .await?;
+
+    if let Some(lockout) = lockout_seconds(failed_attempts, free_attempts) {
+        warn!(
+            "Locking {} '{}' for {} seconds after {} attempts",
+            kind, key, lockout, failed_attempts
+        );
+
+        store.lock_logins(kind, key, lockout).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn record_failed_login(
+    store: &Store,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<(), AppError> {
+    record_failure(store, USERNAME_KIND, username, USERNAME_FREE_ATTEMPTS).await?;
+    if let Some(ip_address) = ip_address {
+        record_failure(store, IP_ADDRESS_KIND, ip_address, IP_ADDRESS_FREE_ATTEMPTS).await?;
+    }
+
+    Ok(())
+}
+
+/// Forgets the failures for a username after a successful login. Failures from
+/// the address are kept, so logging in to one account doesn't reset the limit
+/// for guessing at others.
+pub(crate) async fn clear_failed_logins(store: &Store, username: &str) -> Result<(), AppError> {
+    store.clear_login_failures(USERNAME_KIND, username).await
+}
+
+/// Returns how many seconds are left before another password reset may be
+/// asked for the email or from the address, if either is currently locked.
+pub(crate) async fn reset_lockout(
+    store: &Store,
+    email: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<i64>, AppError> {
+    let email_lockout = store.login_lockout(RESET_EMAIL_KIND, email).await?;
+    let ip_address_lockout = match ip_address {
+        Some(ip_address) => store.login_lockout(RESET_IP_ADDRESS_KIND, ip_address).await?,
+        None => None,
+    };
+
+    Ok(email_lockout.max(ip_address_lockout))
+}
+
+/// Counts a password reset request, whether or not the email belongs to anyone.
+pub(crate) async fn record_reset_request(
+    store: &Store,
+    email: &str,
+    ip_address: Option<&str>,
+) -> Result<(), AppError> {
+    record_failure(store, RESET_EMAIL_KIND, email, RESET_EMAIL_FREE_REQUESTS).await?;
+    if let Some(ip_address) = ip_address {
+        record_failure(
+            store,
+            RESET_IP_ADDRESS_KIND,
+            ip_address,
+            RESET_IP_ADDRESS_FREE_REQUESTS,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse, Redirect},
+    Extension, Form,
+};
+use tracing::info;
+
+use crate::{
+    auth::{AuthState, Credential, Scope},
+    errors::{AppError, NotLoggedIn, TokenNameError},
+    flash::Flashes,
+    repository::Store,
+    roles::Permission,
+    sessions::format_time,
+    utils::{page_context, random_hex},
+    Random, Templates,
+};
+
+/// Marks personal access tokens, so they can be told apart from the access
+/// tokens handed out by `/api/token`.
+const TOKEN_PREFIX: &str = "pat_";
+const MAX_TOKEN_NAME_LENGTH: usize = 100;
+
+#[derive(serde::Serialize)]
+struct TokenInfo {
+    id: i32,
+    name: String,
+    scopes: Vec<&'static str>,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+/// Unknown scope names are skipped, so a token never gets more than it was
+/// given.
+fn parse_scopes(scopes: &[String]) -> Vec<Scope> {
+    scopes.iter().filter_map(|scope| scope.parse().ok()).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+pub(crate) fn is_personal_access_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Looks up a personal access token and records that it was used.
+pub(crate) async fn personal_access_token_credential(
+    store: &Store,
+    token: &str,
+) -> Result<Option<Credential>, AppError> {
+    let grant = store.use_access_token(&hash_token(token)).await?;
+
+    Ok(grant.map(|grant| Credential::AccessToken {
+        user_id: grant.user_id,
+        scopes: parse_scopes(&grant.scopes),
+    }))
+}
+
+async fn get_tokens(store: &Store, user_id: i32) -> Result<Vec<TokenInfo>, AppError> {
+    let tokens = store.access_tokens(user_id).await?;
+
+    Ok(tokens
+        .into_iter()
+        .map(|token| TokenInfo {
+            id: token.id,
+            name: token.name,
+            scopes: parse_scopes(&token.scopes)
+                .into_iter()
+                .map(Scope::as_str)
+                .collect(),
+            created_at: format_time(token.created_at),
+            last_used_at: token.last_used_at.map(format_time),
+        })
+        .collect())
+}
+
+/// Renders the token list. `new_token` is shown only on the page straight after
+/// it was created, since only its hash is kept.
+async fn tokens_page(
+    mut current_user: AuthState,
+    flashes: &Flashes,
+    store: &Store,
+    templates: &Templates,
+    new_token: Option<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut context = page_context(&current_user, flashes);
+    let is_admin = current_user.has_permission(Permission::ViewAdmin).await?;
+    let user = current_user.require_user().await?;
+
+    context.insert("tokens", &get_tokens(store, user.id).await?);
+    context.insert("is_admin", &is_admin);
+    if let Some(new_token) = new_token {
+        context.insert("new_token", &new_token);
+    }
+
+    Ok(Html(templates.render("tokens", &context)?))
+}
+
+/// Tokens can only be managed from a session, so a leaked token can't be used
+/// to make more of itself.
+pub(crate) async fn tokens(
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    if current_user.session_token().is_none() {
+        return Err(NotLoggedIn.into());
+    }
+
+    tokens_page(current_user, &flashes, &store, &templates, None).await
+}
+
+pub(crate) async fn create_token(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(templates): Extension<Templates>,
+    Form(TokenForm {
+        name,
+        profile_read,
+        profile_write,
+        admin,
+    }): Form<TokenForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if current_user.session_token().is_none() {
+        return Err(NotLoggedIn.into());
+    }
+
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.len() > MAX_TOKEN_NAME_LENGTH {
+        return Err(TokenNameError.into());
+    }
+
+    // Only staff can hand out the admin scope.
+    let is_admin = current_user.has_permission(Permission::ViewAdmin).await?;
+    let scopes: Vec<&str> = [
+        (Scope::ProfileRead, profile_read.is_some()),
+        (Scope::ProfileWrite, profile_write.is_some()),
+        (Scope::Admin, admin.is_some() && is_admin),
+    ]
+    .into_iter()
+    .filter(|(_, granted)| *granted)
+    .map(|(scope, _)| scope.as_str())
+    .collect();
+
+    let user = current_user.require_user().await?;
+
+    let token = format!("{}{}", TOKEN_PREFIX, random_hex(random, 32));
+
+    store
+        .create_access_token(user.id, &name, &hash_token(&token), &scopes)
+        .await?;
+
+    info!("User '{}' created access token '{}'", user.username, name);
+
+    tokens_page(current_user, &flashes, &store, &templates, Some(token)).await
+}
+
+pub(crate) async fn revoke_token(
+    Path(id): Path<i32>,
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+) -> Result<impl IntoResponse, AppError> {
+    if current_user.session_token().is_none() {
+        return Err(NotLoggedIn.into());
+    }
+
+    let user = current_user.require_user().await?;
+
+    store.delete_access_token(user.id, id).await?;
+    flashes.success("The access token has been revoked");
+
+    Ok(Redirect::to("/me/tokens"))
+}
+
+/// Checkboxes are only sent when ticked, so each scope is its own field.
+#[derive(serde::Deserialize)]
+pub(crate) struct TokenForm {
+    name: String,
+    profile_read: Option<String>,
+    profile_write: Option<String>,
+    admin: Option<String>,
+}
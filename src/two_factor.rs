@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use axum::{
+    http::{header::SET_COOKIE, HeaderMap, HeaderValue},
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension, Form,
+};
+use rand_core::RngCore;
+use time::OffsetDateTime;
+use totp_rs::{Algorithm, TotpUrlError, TOTP};
+use tracing::info;
+
+use crate::{
+    auth::{confirm_password, new_session, AuthState, ClientInfo, Scope},
+    config::Config,
+    errors::{AppError, LoginError, MissingScope, TwoFactorError},
+    flash::Flashes,
+    password::PasswordHashing,
+    repository::Store,
+    throttle::{clear_failed_logins, login_lockout, record_failed_login},
+    utils::{
+        clear_login_challenge_cookie, get_cookie, login_response, page_context, random_hex,
+    },
+    Random, Templates, LOGIN_CHALLENGE_COOKIE_NAME, LOGIN_CHALLENGE_LIFETIME_SECONDS,
+};
+
+const ISSUER: &str = "Hecksmosis";
+const RECOVERY_CODE_COUNT: usize = 10;
+/// A login challenge is thrown away after this many wrong codes.
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// Fails if the stored secret is too short or the username can't be used as
+/// an account name.
+fn totp(secret: Vec<u8>, username: &str) -> Result<TOTP, TotpUrlError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        30,
+        secret,
+        Some(ISSUER.to_owned()),
+        username.to_owned(),
+    )
+}
+
+/// Returns the time step a code was generated for, allowing one step of clock
+/// drift either way.
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let current_step = OffsetDateTime::now_utc().unix_timestamp() as u64 / totp.step;
+
+    (current_step - 1..=current_step + 1)
+        .find(|step| totp.check(code, step * totp.step))
+        .map(|step| step as i64)
+}
+
+/// Recovery codes are compared without dashes, spaces or case so they can be
+/// typed however they were written down.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hash_secret_token(token: &str) -> String {
+    sha256::digest(token)
+}
+
+async fn create_recovery_codes(
+    store: &Store,
+    random: Random,
+    user_id: i32,
+) -> Result<Vec<String>, AppError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut code_hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = random_hex(random.clone(), 5);
+        code_hashes.push(hash_secret_token(&code));
+        codes.push(format!("{}-{}", &code[..5], &code[5..]));
+    }
+
+    store.set_recovery_codes(user_id, &code_hashes).await?;
+
+    Ok(codes)
+}
+
+/// Checks a TOTP code or recovery code for a user with two-factor
+/// authentication enabled. Each TOTP code and recovery code only works once.
+async fn verify_code(store: &Store, user_id: i32, code: &str) -> Result<bool, AppError> {
+    let Some(user) = store.user_by_id(user_id).await? else {
+        return Ok(false);
+    };
+    let Some(secret) = store
+        .totp_secrets(user_id)
+        .await?
+        .and_then(|secrets| secrets.secret)
+    else {
+        return Ok(false);
+    };
+
+    let code = code.trim();
+    if let Some(step) = matching_step(&totp(secret, &user.username)?, code) {
+        return store.use_totp_step(user_id, step).await;
+    }
+
+    let code_hash = hash_secret_token(&normalize_recovery_code(code));
+    if store.use_recovery_code(user_id, &code_hash).await? {
+        info!("User '{}' logged in with a recovery code", user.username);
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub(crate) async fn create_login_challenge(
+    store: &Store,
+    random: Random,
+    user_id: i32,
+) -> Result<String, AppError> {
+    let challenge = random_hex(random, 32);
+
+    store
+        .create_login_challenge(
+            &hash_secret_token(&challenge),
+            user_id,
+            LOGIN_CHALLENGE_LIFETIME_SECONDS,
+        )
+        .await?;
+
+    Ok(challenge)
+}
+
+pub(crate) async fn get_two_factor_login(
+    headers: HeaderMap,
+    Extension(current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(templates): Extension<Templates>,
+) -> Result<Response, AppError> {
+    if get_cookie(&headers, &config.cookies.name(LOGIN_CHALLENGE_COOKIE_NAME)).is_none() {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    let page = templates.render("two_factor_login", &page_context(&current_user, &flashes))?;
+    Ok(Html(page).into_response())
+}
+
+pub(crate) async fn post_two_factor_login(
+    headers: HeaderMap,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(client_info): Extension<ClientInfo>,
+    Extension(config): Extension<Arc<Config>>,
+    Form(CodeForm { code }): Form<CodeForm>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(challenge) = get_cookie(&headers, &config.cookies.name(LOGIN_CHALLENGE_COOKIE_NAME))
+    else {
+        return Err(TwoFactorError::ChallengeExpired.into());
+    };
+
+    let user_id = redeem_login_challenge(&store, &client_info, &challenge, &code).await?;
+
+    let session_token = new_session(&store, random, user_id, &client_info).await?;
+
+    let mut response = login_response(&config.cookies, session_token).into_response();
+    if let Ok(value) = HeaderValue::from_str(&clear_login_challenge_cookie(&config.cookies)) {
+        response.headers_mut().append(SET_COOKIE, value);
+    }
+    Ok(response)
+}
+
+/// Checks a second factor for a user whose password was right. Wrong codes
+/// count as failed logins, so guessing is throttled the same way as guessing
+/// passwords, and a right one forgets the earlier failures.
+pub(crate) async fn verify_second_factor(
+    store: &Store,
+    client_info: &ClientInfo,
+    user_id: i32,
+    username: &str,
+    code: &str,
+) -> Result<bool, AppError> {
+    let ip_address = client_info.ip_address.as_deref();
+    if let Some(retry_after) = login_lockout(store, username, ip_address).await? {
+        info!("Second factor for user '{}' throttled", username);
+        return Err(LoginError::TooManyAttempts(retry_after).into());
+    }
+
+    if !verify_code(store, user_id, code).await? {
+        info!("Invalid second factor for user '{}'", username);
+        record_failed_login(store, username, ip_address).await?;
+        return Ok(false);
+    }
+
+    clear_failed_logins(store, username).await?;
+
+    Ok(true)
+}
+
+/// Checks a second factor against a login challenge and returns the user it
+/// was for. The challenge is used up on success or after too many wrong codes.
+pub(crate) async fn redeem_login_challenge(
+    store: &Store,
+    client_info: &ClientInfo,
+    challenge: &str,
+    code: &str,
+) -> Result<i32, AppError> {
+    let challenge_hash = hash_secret_token(challenge);
+
+    let user = match store.login_challenge_user(&challenge_hash).await? {
+        Some(user_id) => store.user_by_id(user_id).await?,
+        None => None,
+    };
+    let Some(user) = user else {
+        return Err(TwoFactorError::ChallengeExpired.into());
+    };
+
+    if !verify_second_factor(store, client_info, user.id, &user.username, code).await? {
+        let failed_attempts = store.fail_login_challenge(&challenge_hash).await?;
+        if failed_attempts >= MAX_CHALLENGE_ATTEMPTS {
+            store.delete_login_challenge(&challenge_hash).await?;
+        }
+
+        return Err(TwoFactorError::InvalidCode.into());
+    }
+
+    store.delete_login_challenge(&challenge_hash).await?;
+
+    Ok(user.id)
+}
+
+pub(crate) async fn two_factor(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileRead) {
+        return Err(MissingScope(Scope::ProfileRead).into());
+    }
+
+    let mut context = page_context(&current_user, &flashes);
+    let user = current_user.require_user().await?;
+
+    let secrets = store.totp_secrets(user.id).await?.unwrap_or_default();
+    let enabled = secrets.secret.is_some();
+
+    context.insert("enabled", &enabled);
+
+    if enabled {
+        let recovery_codes_left = store.recovery_codes_left(user.id).await?;
+        context.insert("recovery_codes_left", &recovery_codes_left);
+    } else if let Some(secret) = secrets.pending_secret {
+        let totp = totp(secret, &user.username)?;
+        context.insert("secret", &totp.get_secret_base32());
+        context.insert("otpauth_uri", &totp.get_url());
+    }
+
+    Ok(Html(templates.render("two_factor", &context)?))
+}
+
+/// Makes a new secret for the user to add to their authenticator. It only
+/// takes effect once a code for it is entered.
+pub(crate) async fn setup_two_factor(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    let user = current_user.require_user().await?;
+
+    let mut secret = vec![0u8; 20];
+    random.lock().unwrap().fill_bytes(&mut secret);
+
+    store.set_pending_totp_secret(user.id, &secret).await?;
+
+    Ok(Redirect::to("/me/2fa"))
+}
+
+pub(crate) async fn enable_two_factor(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(random): Extension<Random>,
+    Extension(templates): Extension<Templates>,
+    Form(CodeForm { code }): Form<CodeForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    let user = current_user.require_user().await?;
+
+    let Some(pending_secret) = store
+        .totp_secrets(user.id)
+        .await?
+        .and_then(|secrets| secrets.pending_secret)
+    else {
+        return Err(TwoFactorError::InvalidCode.into());
+    };
+
+    let Some(step) = matching_step(&totp(pending_secret, &user.username)?, code.trim()) else {
+        return Err(TwoFactorError::InvalidCode.into());
+    };
+
+    store.enable_totp(user.id, step).await?;
+
+    info!("User '{}' enabled two-factor authentication", user.username);
+
+    let recovery_codes = create_recovery_codes(&store, random, user.id).await?;
+
+    let mut context = page_context(&current_user, &flashes);
+    context.insert("recovery_codes", &recovery_codes);
+    Ok(Html(templates.render("two_factor", &context)?))
+}
+
+pub(crate) async fn disable_two_factor(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Form(PasswordForm { password }): Form<PasswordForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    let user = current_user.require_user().await?;
+
+    if !confirm_password(&store, &hashing, &client_info, &user.username, &password).await? {
+        return Err(TwoFactorError::WrongPassword.into());
+    }
+
+    store.disable_totp(user.id).await?;
+
+    info!("User '{}' disabled two-factor authentication", user.username);
+    flashes.warning("Two-factor authentication has been turned off");
+
+    Ok(Redirect::to("/me/2fa"))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn regenerate_recovery_codes(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(hashing): Extension<PasswordHashing>,
+    Extension(client_info): Extension<ClientInfo>,
+    Extension(random): Extension<Random>,
+    Extension(templates): Extension<Templates>,
+    Form(PasswordForm { password }): Form<PasswordForm>,
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
+
+    let user = current_user.require_user().await?;
+
+    if !confirm_password(&store, &hashing, &client_info, &user.username, &password).await? {
+        return Err(TwoFactorError::WrongPassword.into());
+    }
+
+    let enabled = store
+        .totp_secrets(user.id)
+        .await?
+        .is_some_and(|secrets| secrets.secret.is_some());
+
+    if !enabled {
+        return Ok(Redirect::to("/me/2fa").into_response());
+    }
+
+    let recovery_codes = create_recovery_codes(&store, random, user.id).await?;
+
+    let mut context = page_context(&current_user, &flashes);
+    context.insert("recovery_codes", &recovery_codes);
+    Ok(Html(templates.render("two_factor", &context)?).into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct CodeForm {
+    code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct PasswordForm {
+    password: String,
+}
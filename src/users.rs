@@ -1,181 +1,281 @@
 use axum::{
     extract::Path,
-    response::{Html, IntoResponse, Redirect},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
     Extension, Form,
 };
-use tera::Context;
 
 use crate::{
-    auth::{get_user, is_logged_in_user, AuthState},
-    errors::{NoUser, NotAdmin, NotLoggedIn},
-    utils::error_page,
-    Database, Templates,
+    auth::{change_email, is_logged_in_user, AuthState, Scope},
+    errors::{
+        AppError, EmailError, ErrorInfo, MissingScope, NoUser, NotAdmin, NotLoggedIn, RoleError,
+    },
+    flash::Flashes,
+    repository::{Profile, Store},
+    roles::{Permission, ADMIN_ROLE, MODERATOR_ROLE, USER_ROLE},
+    utils::page_context,
+    validation::{check_email, check_profile, form_context, normalize_email, FieldErrors},
+    Templates,
 };
 
-async fn get_users(database: &Database) -> Vec<String> {
-    const QUERY: &str = "SELECT username FROM users LIMIT 100";
+/// How many users the user list and administration page show.
+const USER_LIST_LIMIT: i64 = 100;
 
-    sqlx::query_as(QUERY)
-        .fetch_all(database)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|(value,)| value)
-        .collect()
+pub(crate) async fn users(
+    Extension(auth_state): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = store.usernames(USER_LIST_LIMIT).await?;
+
+    let mut context = page_context(&auth_state, &flashes);
+    context.insert("users", &users);
+
+    Ok(Html(templates.render("users", &context)?))
 }
 
-async fn get_admins(database: &Database) -> Vec<String> {
-    const QUERY: &str = "SELECT username FROM users WHERE permission_level = 1 LIMIT 100";
+/// What the forms on a user's own page are filled in with.
+#[derive(serde::Serialize)]
+struct OwnPageValues {
+    profile: String,
+    email: String,
+}
 
-    sqlx::query_as(QUERY)
-        .fetch_all(database)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|(value,)| value)
-        .collect()
+/// Renders the logged-in user's own page, with its forms filled in with
+/// `values` and any `errors` shown next to their fields.
+async fn own_page(
+    auth_state: &mut AuthState,
+    flashes: &Flashes,
+    templates: &Templates,
+    values: OwnPageValues,
+    errors: &FieldErrors,
+) -> Result<String, AppError> {
+    let mut context = form_context(auth_state, flashes, &values, errors);
+    let user = auth_state.require_user().await?;
+    context.insert("username", &user.username);
+    context.insert("is_self", &true);
+    context.insert("role", &user.role.name);
+    Ok(templates.render("user", &context)?)
 }
 
-pub(crate) async fn users(
-    Extension(database): Extension<Database>,
+pub(crate) async fn profile(
+    Extension(mut current_user): Extension<AuthState>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
     Extension(templates): Extension<Templates>,
-) -> impl IntoResponse {
-    let users = get_users(&database).await;
+    Form(ProfileForm { profile }): Form<ProfileForm>,
+) -> Result<Response, AppError> {
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
+    }
 
-    let mut context = Context::new();
-    context.insert("users", &users);
+    let user = current_user.require_user().await?;
+    let (user_id, email) = (user.id, user.email.clone().unwrap_or_default());
+
+    let mut errors = FieldErrors::default();
+    errors.check("profile", check_profile(&profile));
+    if !errors.is_empty() {
+        let values = OwnPageValues { profile, email };
+        let page = own_page(&mut current_user, &flashes, &templates, values, &errors).await?;
+        return Ok((StatusCode::BAD_REQUEST, Html(page)).into_response());
+    }
+
+    store.set_profile(user_id, &profile).await?;
+    flashes.success("Your profile has been saved");
 
-    Html(templates.render("users", &context).unwrap())
+    Ok(Redirect::to("/me").into_response())
 }
 
-pub(crate) async fn profile(
+pub(crate) async fn email(
     Extension(mut current_user): Extension<AuthState>,
-    Extension(database): Extension<Database>,
-    Form(ProfileForm { profile }): Form<ProfileForm>,
-) -> impl IntoResponse {
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
+    Extension(templates): Extension<Templates>,
+    Form(EmailForm { email }): Form<EmailForm>,
+) -> Result<Response, AppError> {
     if !current_user.logged_in() {
-        return Err(error_page(&NotLoggedIn));
+        return Err(NotLoggedIn.into());
+    }
+
+    if !current_user.allows(Scope::ProfileWrite) {
+        return Err(MissingScope(Scope::ProfileWrite).into());
     }
 
-    let user = current_user.get_user().await.unwrap();
+    let new_email = normalize_email(&email);
+    let new_email = new_email.as_deref();
 
-    const QUERY: &str = "UPDATE users SET profile = $1 WHERE username = $2;";
+    let mut errors = FieldErrors::default();
+    errors.check("email", check_email(new_email));
+    if errors.is_empty() {
+        match change_email(current_user.clone(), new_email).await {
+            Ok(()) => {
+                match new_email {
+                    Some(_) => flashes.success("Your email address has been saved"),
+                    None => flashes.warning(
+                        "Your email address has been removed, so you won't be able to reset your password",
+                    ),
+                }
 
-    sqlx::query(QUERY)
-        .bind(&profile)
-        .bind(&user.username)
-        .execute(&database)
-        .await
-        .unwrap();
+                return Ok(Redirect::to("/me").into_response());
+            }
+            Err(error) => match error.downcast_ref::<EmailError>() {
+                Some(email_error) => errors.add("email", email_error.to_string()),
+                None => return Err(error),
+            },
+        }
+    }
 
-    Ok(Redirect::to("/me"))
+    let username = current_user.require_user().await?.username.clone();
+    let profile = store.profile(&username).await?.and_then(|profile| profile.profile);
+    let values = OwnPageValues {
+        profile: profile.unwrap_or_default(),
+        email,
+    };
+    let page = own_page(&mut current_user, &flashes, &templates, values, &errors).await?;
+    Ok((StatusCode::BAD_REQUEST, Html(page)).into_response())
 }
 
 pub(crate) async fn user(
     Path(username): Path<String>,
     Extension(mut auth_state): Extension<AuthState>,
-    Extension(database): Extension<Database>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
     Extension(templates): Extension<Templates>,
-) -> impl IntoResponse {
-    if let Some((username, profile, permission_level)) = get_user(&username, &database).await {
-        let user_is_self = is_logged_in_user(&mut auth_state, &username).await;
-
-        let _ = PermissionLevel::from(permission_level);
-        // TODO: Add admin page
-
-        let mut context = Context::new();
-        context.insert("username", &username);
-        context.insert("is_self", &user_is_self);
-        if profile.is_none() {
-            context.insert("profile", &"No profile set");
-        } else {
-            context.insert("profile", &profile.unwrap());
-        }
-        Ok(Html(templates.render("user", &context).unwrap()))
-    } else {
-        Err(error_page(&NoUser(username)))
+) -> Result<impl IntoResponse, AppError> {
+    let Some(Profile { username, profile }) = store.profile(&username).await? else {
+        return Err(NoUser(username).into());
+    };
+
+    // Private details are only shown to tokens that may read the profile.
+    let user_is_self = auth_state.allows(Scope::ProfileRead)
+        && is_logged_in_user(&mut auth_state, &username).await?;
+
+    if user_is_self {
+        let email = auth_state.require_user().await?.email.clone();
+        let values = OwnPageValues {
+            profile: profile.unwrap_or_default(),
+            email: email.unwrap_or_default(),
+        };
+        let errors = FieldErrors::default();
+        return Ok(Html(own_page(&mut auth_state, &flashes, &templates, values, &errors).await?));
     }
+
+    let mut context = page_context(&auth_state, &flashes);
+    context.insert("username", &username);
+    context.insert("is_self", &false);
+    context.insert("profile", &profile.unwrap_or_else(|| "No profile set".to_owned()));
+    Ok(Html(templates.render("user", &context)?))
 }
 
 pub(crate) async fn me(
     Extension(mut current_user): Extension<AuthState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    if let Some(user) = current_user.get_user().await {
-        Ok(Redirect::to(&format!("/user/{}", user.username)))
-    } else {
-        Err(error_page(&NotLoggedIn))
+) -> Result<impl IntoResponse, AppError> {
+    if !current_user.allows(Scope::ProfileRead) {
+        return Err(MissingScope(Scope::ProfileRead).into());
     }
+
+    let user = current_user.require_user().await?;
+    Ok(Redirect::to(&format!("/user/{}", user.username)))
 }
 
 pub(crate) async fn admin(
     Extension(mut auth_state): Extension<AuthState>,
-    Extension(database): Extension<Database>,
+    Extension(flashes): Extension<Flashes>,
+    Extension(store): Extension<Store>,
     Extension(templates): Extension<Templates>,
-) -> impl IntoResponse {
-    if auth_state.is_admin().await {
-        let current_username = &auth_state.get_user().await.unwrap().username;
-        let users = get_users(&database).await;
-        let admins = get_admins(&database)
-            .await
-            .iter()
-            .filter(|&n| n != current_username)
-            .map(String::from)
-            .collect::<Vec<_>>();
-        let mut context = Context::new();
-        context.insert("users", &users);
-        context.insert("admins", &admins);
-        Ok(Html(templates.render("admin", &context).unwrap()))
-    } else {
-        Err(error_page(&NotAdmin))
+) -> Result<impl IntoResponse, AppError> {
+    if !auth_state.has_permission(Permission::ViewAdmin).await? {
+        return Err(NotAdmin.into());
     }
+
+    let current_username = auth_state.require_user().await?.username.clone();
+    let can_assign_roles = auth_state.has_permission(Permission::AssignRoles).await?;
+    let users = store.user_roles(USER_LIST_LIMIT).await?;
+    let mut context = page_context(&auth_state, &flashes);
+    context.insert("users", &users);
+    context.insert("current_username", &current_username);
+    context.insert("can_assign_roles", &can_assign_roles);
+    Ok(Html(templates.render("admin", &context)?))
 }
 
 pub(crate) async fn add_admin(
     Path(username): Path<String>,
-    Extension(database): Extension<Database>,
+    Extension(store): Extension<Store>,
     Extension(mut auth_state): Extension<AuthState>,
-) -> impl IntoResponse {
-    if auth_state.is_admin().await {
-        let user = get_user(&username, &database).await;
-        if let Some((_, _, permission_level)) = user {
-            if permission_level == 0 {
-                const QUERY: &str = "UPDATE users SET permission_level = 1 WHERE username = $1;";
-
-                sqlx::query(QUERY)
-                    .bind(&username)
-                    .execute(&database)
-                    .await
-                    .unwrap();
-            }
-        }
-        Ok(Redirect::to("/admin"))
-    } else {
-        Err(error_page(&NotAdmin))
+    Extension(flashes): Extension<Flashes>,
+    form: Option<Form<ConfirmForm>>,
+) -> Result<impl IntoResponse, AppError> {
+    if !auth_state.has_permission(Permission::AssignRoles).await? {
+        return Err(NotAdmin.into());
     }
+
+    let changed = if is_confirmed(form) {
+        store.change_role(&username, ADMIN_ROLE, &[USER_ROLE, MODERATOR_ROLE]).await
+    } else {
+        Err(RoleError::ConfirmationRequired.into())
+    };
+
+    report_role_change(
+        &flashes,
+        changed,
+        format!("{} is now an admin", username),
+        format!("{} can't be made an admin", username),
+    )?;
+
+    Ok(Redirect::to("/admin"))
 }
 
 pub(crate) async fn remove_admin(
     Path(username): Path<String>,
-    Extension(database): Extension<Database>,
+    Extension(store): Extension<Store>,
     Extension(mut auth_state): Extension<AuthState>,
-) -> impl IntoResponse {
-    if auth_state.is_admin().await {
-        let user = get_user(&username, &database).await;
-        if let Some((_, _, permission_level)) = user {
-            if permission_level == 1 {
-                const QUERY: &str = "UPDATE users SET permission_level = 0 WHERE username = $1;";
-
-                sqlx::query(QUERY)
-                    .bind(&username)
-                    .execute(&database)
-                    .await
-                    .unwrap();
-            }
-        }
-        Ok(Redirect::to("/admin"))
+    Extension(flashes): Extension<Flashes>,
+    form: Option<Form<ConfirmForm>>,
+) -> Result<impl IntoResponse, AppError> {
+    if !auth_state.has_permission(Permission::AssignRoles).await? {
+        return Err(NotAdmin.into());
+    }
+
+    let changed = if is_confirmed(form) {
+        store.change_role(&username, USER_ROLE, &[ADMIN_ROLE]).await
     } else {
-        Err(error_page(&NotAdmin))
+        Err(RoleError::ConfirmationRequired.into())
+    };
+
+    report_role_change(
+        &flashes,
+        changed,
+        format!("{} is no longer an admin", username),
+        format!("{} isn't an admin", username),
+    )?;
+
+    Ok(Redirect::to("/admin"))
+}
+
+/// Every role change has to be confirmed by ticking a box next to it.
+fn is_confirmed(form: Option<Form<ConfirmForm>>) -> bool {
+    form.is_some_and(|Form(form)| form.confirm.is_some())
+}
+
+/// Tells the admin on the next page how a role change went. Errors that they
+/// can't do anything about still fail the request.
+fn report_role_change(
+    flashes: &Flashes,
+    changed: Result<bool, AppError>,
+    success: String,
+    unchanged: String,
+) -> Result<(), AppError> {
+    match changed {
+        Ok(true) => flashes.success(success),
+        Ok(false) => flashes.error(unchanged),
+        Err(error @ (AppError::Rejected(_) | AppError::NotFound(_))) => {
+            flashes.error(error.error_info().1)
+        }
+        Err(error) => return Err(error),
     }
+
+    Ok(())
 }
 
 #[derive(serde::Deserialize)]
@@ -183,27 +283,12 @@ pub struct ProfileForm {
     profile: String,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum PermissionLevel {
-    User,
-    Admin,
-}
-
-impl From<PermissionLevel> for i32 {
-    fn from(permission_level: PermissionLevel) -> Self {
-        match permission_level {
-            PermissionLevel::User => 0,
-            PermissionLevel::Admin => 1,
-        }
-    }
+#[derive(serde::Deserialize)]
+pub struct EmailForm {
+    email: String,
 }
 
-impl From<i32> for PermissionLevel {
-    fn from(permission_level: i32) -> Self {
-        match permission_level {
-            0 => PermissionLevel::User,
-            1 => PermissionLevel::Admin,
-            _ => panic!("Invalid permission level"),
-        }
-    }
+#[derive(serde::Deserialize)]
+pub struct ConfirmForm {
+    confirm: Option<String>,
 }
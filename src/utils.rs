@@ -1,39 +1,129 @@
-use crate::{auth::SessionToken, COOKIE_MAX_AGE, USER_COOKIE_NAME, errors::ErrorInfo};
+use crate::{
+    auth::{AuthState, SessionToken}, config::CookieConfig, flash::Flashes, Random,
+    FLASH_COOKIE_NAME, FLASH_LIFETIME_SECONDS, LEGACY_SESSION_COOKIE_NAME,
+    LOGIN_CHALLENGE_COOKIE_NAME, LOGIN_CHALLENGE_LIFETIME_SECONDS, SESSION_COOKIE_NAME,
+    SESSION_LIFETIME_SECONDS,
+};
 use axum::{
     body::Empty,
-    http::{Response, StatusCode},
+    http::{HeaderMap, Response, StatusCode},
     response::IntoResponse,
 };
+use cookie::Cookie;
+use rand_core::RngCore;
+use tera::Context;
+use time::Duration;
+
+/// Returns `bytes` random bytes encoded as lowercase hex.
+pub(crate) fn random_hex(random: Random, bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    random.lock().unwrap().fill_bytes(&mut buffer);
+    buffer.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| Cookie::parse(cookie.trim().to_owned()).ok())
+        .find_map(|cookie| (cookie.name() == name).then(|| cookie.value().to_owned()))
+}
+
+/// Builds a cookie that is scoped to the whole site and hidden from scripts.
+fn build_cookie(config: &CookieConfig, name: &str, value: String, max_age: i64) -> String {
+    Cookie::build(config.name(name), value)
+        .path("/")
+        .http_only(true)
+        .secure(config.secure)
+        .same_site(config.same_site)
+        .max_age(Duration::seconds(max_age))
+        .finish()
+        .to_string()
+}
 
-pub(crate) fn login_response(session_token: SessionToken) -> impl IntoResponse {
+pub(crate) fn session_cookie(config: &CookieConfig, session_token: SessionToken) -> String {
+    build_cookie(
+        config,
+        SESSION_COOKIE_NAME,
+        session_token.into_cookie_value(),
+        SESSION_LIFETIME_SECONDS,
+    )
+}
+
+pub(crate) fn logout_cookie(config: &CookieConfig) -> String {
+    build_cookie(config, SESSION_COOKIE_NAME, String::new(), 0)
+}
+
+/// Expires a session cookie from before the cookies were hardened. Those were
+/// set without a `Path`, so browsers stored them under the path of whichever
+/// page set them.
+pub(crate) fn clear_legacy_session_cookie(path: &str) -> String {
+    Cookie::build(LEGACY_SESSION_COOKIE_NAME, "")
+        .path(path)
+        .max_age(Duration::ZERO)
+        .finish()
+        .to_string()
+}
+
+pub(crate) fn login_challenge_cookie(config: &CookieConfig, challenge: &str) -> String {
+    build_cookie(
+        config,
+        LOGIN_CHALLENGE_COOKIE_NAME,
+        challenge.to_owned(),
+        LOGIN_CHALLENGE_LIFETIME_SECONDS,
+    )
+}
+
+pub(crate) fn clear_login_challenge_cookie(config: &CookieConfig) -> String {
+    build_cookie(config, LOGIN_CHALLENGE_COOKIE_NAME, String::new(), 0)
+}
+
+pub(crate) fn flash_cookie(config: &CookieConfig, notices: String) -> String {
+    build_cookie(config, FLASH_COOKIE_NAME, notices, FLASH_LIFETIME_SECONDS)
+}
+
+pub(crate) fn clear_flash_cookie(config: &CookieConfig) -> String {
+    build_cookie(config, FLASH_COOKIE_NAME, String::new(), 0)
+}
+
+/// Sends the user on to enter their second factor after a correct password.
+pub(crate) fn second_factor_response(config: &CookieConfig, challenge: &str) -> impl IntoResponse {
     Response::builder()
         .status(StatusCode::SEE_OTHER)
-        .header("Location", "/")
-        .header(
-            "Set-Cookie",
-            format!(
-                "{}={}; Max-Age={}",
-                USER_COOKIE_NAME,
-                session_token.into_cookie_value(),
-                COOKIE_MAX_AGE
-            ),
-        )
+        .header("Location", "/login/2fa")
+        .header("Set-Cookie", login_challenge_cookie(config, challenge))
         .body(Empty::new())
         .unwrap()
 }
 
-pub(crate) async fn logout_response() -> impl IntoResponse {
+pub(crate) fn login_response(config: &CookieConfig, session_token: SessionToken) -> impl IntoResponse {
     Response::builder()
         .status(StatusCode::SEE_OTHER)
         .header("Location", "/")
-        .header("Set-Cookie", format!("{}=_; Max-Age=0", USER_COOKIE_NAME,))
+        .header("Set-Cookie", session_cookie(config, session_token))
         .body(Empty::new())
         .unwrap()
 }
 
-pub(crate) fn error_page(err: &dyn ErrorInfo) -> impl IntoResponse {
+pub(crate) async fn logout_response(config: &CookieConfig) -> impl IntoResponse {
     Response::builder()
-        .status(err.error_info().0)
-        .body(err.error_info().1)
+        .status(StatusCode::SEE_OTHER)
+        .header("Location", "/")
+        .header("Set-Cookie", logout_cookie(config))
+        .body(Empty::new())
         .unwrap()
 }
+
+/// Starts a template context with the values every page needs, including the
+/// notices waiting to be shown.
+pub(crate) fn page_context(auth_state: &AuthState, flashes: &Flashes) -> Context {
+    let mut context = Context::new();
+    context.insert("logged_in", &auth_state.logged_in());
+    context.insert("notices", &flashes.take());
+    if let Some(csrf_token) = auth_state.csrf_token() {
+        context.insert("csrf_token", &csrf_token);
+    }
+    context
+}
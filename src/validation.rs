@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use tera::Context;
+
+use crate::{auth::AuthState, flash::Flashes, utils::page_context};
+
+pub(crate) const MIN_USERNAME_LENGTH: usize = 1;
+pub(crate) const MAX_USERNAME_LENGTH: usize = 19;
+/// Passwords shorter than this are rejected on signup and password change.
+pub(crate) const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longest profile text, in characters.
+pub(crate) const MAX_PROFILE_LENGTH: usize = 2000;
+
+/// The limits that forms are checked against, also given to the templates so
+/// that browsers can point out mistakes before the form is sent.
+#[derive(serde::Serialize)]
+struct Rules {
+    min_username_length: usize,
+    max_username_length: usize,
+    username_pattern: &'static str,
+    min_password_length: usize,
+    max_profile_length: usize,
+}
+
+const RULES: Rules = Rules {
+    min_username_length: MIN_USERNAME_LENGTH,
+    max_username_length: MAX_USERNAME_LENGTH,
+    username_pattern: "[0-9a-z-]+",
+    min_password_length: MIN_PASSWORD_LENGTH,
+    max_profile_length: MAX_PROFILE_LENGTH,
+};
+
+pub(crate) fn check_username(username: &str) -> Result<(), String> {
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.len()) {
+        return Err(format!(
+            "Usernames are {} to {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-'))
+    {
+        return Err("Usernames can only use lowercase letters, digits and dashes".to_owned());
+    }
+
+    Ok(())
+}
+
+pub(crate) fn valid_username(username: &str) -> bool {
+    check_username(username).is_ok()
+}
+
+pub(crate) fn check_password(password: &str) -> Result<(), String> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Passwords need at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks an optional email address, where `None` means none was given.
+pub(crate) fn check_email(email: Option<&str>) -> Result<(), String> {
+    match email {
+        Some(email) if !valid_email(email) => Err("Enter a valid email address".to_owned()),
+        _ => Ok(()),
+    }
+}
+
+/// Addresses are kept trimmed and in lowercase, so an account is found however
+/// its address is typed. An empty address means none was given.
+pub(crate) fn normalize_email(email: &str) -> Option<String> {
+    Some(email.trim().to_lowercase()).filter(|email| !email.is_empty())
+}
+
+pub(crate) fn valid_email(email: &str) -> bool {
+    email.parse::<lettre::Address>().is_ok()
+}
+
+pub(crate) fn check_profile(profile: &str) -> Result<(), String> {
+    if profile.chars().count() > MAX_PROFILE_LENGTH {
+        return Err(format!(
+            "Profiles can be at most {} characters long",
+            MAX_PROFILE_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+/// The problems found in a submitted form, each kept with the name of the field
+/// it is about so the form can be shown again with the message next to it.
+/// Problems with the form as a whole go under `form`.
+#[derive(Default, serde::Serialize)]
+pub(crate) struct FieldErrors(BTreeMap<&'static str, String>);
+
+impl FieldErrors {
+    /// Adds an error for `field`, unless it already has one.
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_insert_with(|| message.into());
+    }
+
+    /// Adds the error from one of the checks above, if it failed.
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.add(field, message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Starts the context for a page with a form on it, filled in with `values`.
+/// Passwords should be left out of `values` so they are never sent back.
+pub(crate) fn form_context(
+    auth_state: &AuthState,
+    flashes: &Flashes,
+    values: &impl serde::Serialize,
+    errors: &FieldErrors,
+) -> Context {
+    let mut context = page_context(auth_state, flashes);
+    context.insert("rules", &RULES);
+    context.insert("values", values);
+    context.insert("errors", errors);
+    context
+}
@@ -0,0 +1,26 @@
+use hecksmosis::{run_migrations, MIGRATOR};
+use sqlx::PgPool;
+
+/// Starts from an empty database, which `sqlx::test` creates from the server
+/// at `DATABASE_URL`.
+#[sqlx::test(migrations = false)]
+async fn migrates_empty_database_to_latest(database: PgPool) {
+    run_migrations(&database).await.unwrap();
+
+    let latest = MIGRATOR.iter().map(|migration| migration.version).max();
+    let applied: Option<i64> =
+        sqlx::query_scalar("SELECT max(version) FROM _sqlx_migrations WHERE success;")
+            .fetch_one(&database)
+            .await
+            .unwrap();
+    assert_eq!(applied, latest);
+
+    let roles: Vec<String> = sqlx::query_scalar("SELECT name FROM roles ORDER BY level;")
+        .fetch_all(&database)
+        .await
+        .unwrap();
+    assert_eq!(roles, ["user", "admin", "moderator", "owner"]);
+
+    // Running again finds nothing left to apply.
+    run_migrations(&database).await.unwrap();
+}
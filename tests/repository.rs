@@ -0,0 +1,262 @@
+use hecksmosis::{
+    repository::{
+        AccessTokenGrant, AdminBootstrap, ClientInfo, EmailError, MemoryStore, PostgresStore,
+        Repository, RoleError, SessionIds, SessionToken, SignupError, SqliteStore,
+    },
+    MIGRATOR,
+};
+use sqlx::{sqlite::SqlitePoolOptions, PgPool};
+
+const LIFETIME_SECONDS: i64 = 60;
+
+fn token(value: &str) -> SessionToken {
+    value.parse().unwrap()
+}
+
+/// The behaviour every store has to share.
+async fn check_store(store: &dyn Repository) {
+    let client_info = ClientInfo::default();
+
+    let alice = store.create_user("alice", Some("alice@example.com"), "hash").await.unwrap();
+    let error = store.create_user("alice", None, "hash").await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(SignupError::UsernameExists)));
+    let error = store.create_user("bob", Some("alice@example.com"), "hash").await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(SignupError::EmailExists)));
+    let bob = store.create_user("bob", None, "hash").await.unwrap();
+
+    let record = store.user_by_id(alice).await.unwrap().unwrap();
+    assert_eq!(record.username, "alice");
+    assert_eq!(record.role_name.as_deref(), Some("user"));
+    assert!(record.permissions.is_empty());
+    assert_eq!(store.user_id("bob").await.unwrap(), Some(bob));
+    assert_eq!(store.login_details("alice").await.unwrap().unwrap().password_hash, "hash");
+    assert_eq!(store.user_by_email("alice@example.com").await.unwrap().unwrap().id, alice);
+    assert!(store.user_by_email("nobody@example.com").await.unwrap().is_none());
+
+    store.set_password_hash(alice, "new hash").await.unwrap();
+    assert_eq!(store.password_hash(alice).await.unwrap().as_deref(), Some("new hash"));
+    let error = store.set_email(bob, Some("alice@example.com")).await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(EmailError::EmailExists)));
+    store.set_email(bob, Some("bob@example.com")).await.unwrap();
+    store.set_profile(bob, "Hello").await.unwrap();
+    assert_eq!(store.profile("bob").await.unwrap().unwrap().profile.as_deref(), Some("Hello"));
+
+    assert!(store.change_role("alice", "admin", &["user"]).await.unwrap());
+    assert!(!store.change_role("alice", "admin", &["user"]).await.unwrap());
+    let record = store.user_by_id(alice).await.unwrap().unwrap();
+    assert!(record.permissions.iter().any(|permission| permission == "roles.assign"));
+    let roles = store.user_roles(100).await.unwrap();
+    assert_eq!(roles[0].username, "alice");
+    assert_eq!(roles[0].role, "admin");
+
+    // Alice is the only admin, so she can't be demoted or deleted.
+    let error = store.change_role("alice", "user", &["admin"]).await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(RoleError::LastAdmin)));
+    let error = store.delete_user(alice).await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(RoleError::LastAdmin)));
+
+    store.create_session(token("1"), alice, LIFETIME_SECONDS, &client_info).await.unwrap();
+    store.create_session(token("2"), alice, LIFETIME_SECONDS, &client_info).await.unwrap();
+    store.create_session(token("3"), alice, -1, &client_info).await.unwrap();
+    assert!(store.refresh_session(token("1"), LIFETIME_SECONDS, &client_info).await.unwrap());
+    assert_eq!(store.session_user(token("1")).await.unwrap(), Some(alice));
+    assert!(!store.refresh_session(token("3"), LIFETIME_SECONDS, &client_info).await.unwrap());
+    assert_eq!(store.session_user(token("3")).await.unwrap(), None);
+
+    let sessions = store.user_sessions(token("1")).await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+
+    assert_eq!(store.delete_user_sessions(alice, Some(token("1"))).await.unwrap(), 1);
+    assert_eq!(store.session_user(token("2")).await.unwrap(), None);
+    assert_eq!(store.session_user(token("1")).await.unwrap(), Some(alice));
+
+    store.create_session(token("4"), bob, LIFETIME_SECONDS, &client_info).await.unwrap();
+    let bob_session = store.user_sessions(token("4")).await.unwrap()[0].id;
+    assert_eq!(store.revoke_session(token("1"), bob_session).await.unwrap(), None);
+    assert_eq!(store.revoke_session(token("4"), bob_session).await.unwrap(), Some(true));
+
+    // Refresh tokens work once each.
+    let ids = store.set_refresh_token(token("1"), "refresh 1").await.unwrap().unwrap();
+    assert_eq!(ids.user_id, alice);
+    assert_eq!(store.session_by_id(ids).await.unwrap(), Some(token("1")));
+    let other_user = SessionIds { user_id: bob, ..ids };
+    assert_eq!(store.session_by_id(other_user).await.unwrap(), None);
+    assert_eq!(
+        store.swap_refresh_token("refresh 1", "refresh 2", LIFETIME_SECONDS).await.unwrap(),
+        Some(ids)
+    );
+    assert_eq!(
+        store.swap_refresh_token("refresh 1", "refresh 3", LIFETIME_SECONDS).await.unwrap(),
+        None
+    );
+
+    // Two-factor authentication only turns on with a pending secret, and each
+    // time step and recovery code can only be used once.
+    assert!(!store.login_details("alice").await.unwrap().unwrap().two_factor_enabled);
+    store.enable_totp(alice, 1).await.unwrap();
+    assert!(store.totp_secrets(alice).await.unwrap().unwrap().secret.is_none());
+    store.set_pending_totp_secret(alice, b"secret").await.unwrap();
+    store.enable_totp(alice, 1).await.unwrap();
+    let secrets = store.totp_secrets(alice).await.unwrap().unwrap();
+    assert_eq!(secrets.secret.as_deref(), Some(&b"secret"[..]));
+    assert!(secrets.pending_secret.is_none());
+    assert!(store.login_details("alice").await.unwrap().unwrap().two_factor_enabled);
+    store.set_pending_totp_secret(alice, b"other").await.unwrap();
+    assert!(store.totp_secrets(alice).await.unwrap().unwrap().pending_secret.is_none());
+    assert!(!store.use_totp_step(alice, 1).await.unwrap());
+    assert!(store.use_totp_step(alice, 2).await.unwrap());
+
+    let codes = ["code 1".to_owned(), "code 2".to_owned()];
+    store.set_recovery_codes(alice, &codes).await.unwrap();
+    assert_eq!(store.recovery_codes_left(alice).await.unwrap(), 2);
+    assert!(store.use_recovery_code(alice, "code 1").await.unwrap());
+    assert!(!store.use_recovery_code(alice, "code 1").await.unwrap());
+    assert!(!store.use_recovery_code(bob, "code 2").await.unwrap());
+    assert_eq!(store.recovery_codes_left(alice).await.unwrap(), 1);
+
+    store.create_login_challenge("challenge", alice, LIFETIME_SECONDS).await.unwrap();
+    store.create_login_challenge("expired", alice, -1).await.unwrap();
+    assert_eq!(store.login_challenge_user("challenge").await.unwrap(), Some(alice));
+    assert_eq!(store.login_challenge_user("expired").await.unwrap(), None);
+    assert_eq!(store.fail_login_challenge("challenge").await.unwrap(), 1);
+    assert_eq!(store.fail_login_challenge("challenge").await.unwrap(), 2);
+    store.delete_login_challenge("challenge").await.unwrap();
+    assert_eq!(store.login_challenge_user("challenge").await.unwrap(), None);
+
+    store.disable_totp(alice).await.unwrap();
+    assert!(store.totp_secrets(alice).await.unwrap().unwrap().secret.is_none());
+    assert_eq!(store.recovery_codes_left(alice).await.unwrap(), 0);
+
+    store.create_access_token(alice, "first", "token 1", &["profile:read"]).await.unwrap();
+    store.create_access_token(alice, "second", "token 2", &["profile:read", "admin"]).await.unwrap();
+    let tokens = store.access_tokens(alice).await.unwrap();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[0].name, "second");
+    assert_eq!(tokens[0].scopes, ["profile:read", "admin"]);
+    assert!(tokens[0].last_used_at.is_none());
+    assert_eq!(
+        store.use_access_token("token 1").await.unwrap(),
+        Some(AccessTokenGrant { user_id: alice, scopes: vec!["profile:read".to_owned()] })
+    );
+    assert!(store.access_tokens(alice).await.unwrap()[1].last_used_at.is_some());
+    store.delete_access_token(bob, tokens[0].id).await.unwrap();
+    assert!(store.use_access_token("token 2").await.unwrap().is_some());
+    store.delete_access_token(alice, tokens[0].id).await.unwrap();
+    assert!(store.use_access_token("token 2").await.unwrap().is_none());
+
+    // A new reset token replaces the old one.
+    store.create_reset_token(alice, "reset 1", LIFETIME_SECONDS).await.unwrap();
+    store.create_reset_token(alice, "reset 2", LIFETIME_SECONDS).await.unwrap();
+    store.create_reset_token(bob, "expired", -1).await.unwrap();
+    assert_eq!(store.reset_token_user("reset 1").await.unwrap(), None);
+    assert_eq!(store.reset_token_user("expired").await.unwrap(), None);
+    assert_eq!(store.reset_token_user("reset 2").await.unwrap(), Some(alice));
+    assert_eq!(store.redeem_reset_token("reset 2").await.unwrap(), Some(alice));
+    assert_eq!(store.redeem_reset_token("reset 2").await.unwrap(), None);
+
+    assert_eq!(store.record_login_failure("username", "alice", 60).await.unwrap(), 1);
+    assert_eq!(store.record_login_failure("username", "alice", 60).await.unwrap(), 2);
+    assert_eq!(store.record_login_failure("ip", "alice", 60).await.unwrap(), 1);
+    assert_eq!(store.login_lockout("username", "alice").await.unwrap(), None);
+    store.lock_logins("username", "alice", 30).await.unwrap();
+    let lockout = store.login_lockout("username", "alice").await.unwrap().unwrap();
+    assert!((1..=30).contains(&lockout));
+    assert_eq!(store.login_lockout("ip", "alice").await.unwrap(), None);
+    store.clear_login_failures("username", "alice").await.unwrap();
+    assert_eq!(store.login_lockout("username", "alice").await.unwrap(), None);
+    assert_eq!(store.record_login_failure("username", "alice", 60).await.unwrap(), 1);
+
+    store.change_role("bob", "admin", &["user"]).await.unwrap();
+    assert!(store.change_role("alice", "user", &["admin"]).await.unwrap());
+    store.delete_user(alice).await.unwrap();
+    assert_eq!(store.user_id("alice").await.unwrap(), None);
+    assert_eq!(store.session_user(token("1")).await.unwrap(), None);
+    assert_eq!(store.usernames(100).await.unwrap(), ["bob"]);
+
+    store.create_session(token("5"), bob, LIFETIME_SECONDS, &client_info).await.unwrap();
+    let summaries = store.user_summaries().await.unwrap();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].username, "bob");
+    assert_eq!(summaries[0].email.as_deref(), Some("bob@example.com"));
+    assert_eq!(summaries[0].role, "admin");
+    assert_eq!(summaries[0].sessions, 1);
+}
+
+/// Making the first admin, on a store without any users yet.
+async fn check_bootstrap(store: &dyn Repository) {
+    let alice = store.create_user("alice", None, "hash").await.unwrap();
+
+    assert_eq!(store.bootstrap_admin("bob", None).await.unwrap(), AdminBootstrap::NoSuchUser);
+    assert_eq!(store.user_id("bob").await.unwrap(), None);
+    assert_eq!(
+        store.bootstrap_admin("alice", Some("other hash")).await.unwrap(),
+        AdminBootstrap::Promoted
+    );
+    let record = store.user_by_id(alice).await.unwrap().unwrap();
+    assert_eq!(record.role_name.as_deref(), Some("admin"));
+    assert_eq!(store.password_hash(alice).await.unwrap().as_deref(), Some("hash"));
+
+    // Once there is an admin, nobody else is made one.
+    assert_eq!(
+        store.bootstrap_admin("bob", Some("hash")).await.unwrap(),
+        AdminBootstrap::AdminExists
+    );
+    assert_eq!(store.user_id("bob").await.unwrap(), None);
+
+    store.change_role("alice", "moderator", &["admin"]).await.unwrap_err();
+    store.delete_user(alice).await.unwrap_err();
+}
+
+/// Creating the first admin when they don't exist yet.
+async fn check_bootstrap_creates(store: &dyn Repository) {
+    assert_eq!(
+        store.bootstrap_admin("alice", Some("hash")).await.unwrap(),
+        AdminBootstrap::Created
+    );
+    let alice = store.user_id("alice").await.unwrap().unwrap();
+    let record = store.user_by_id(alice).await.unwrap().unwrap();
+    assert_eq!(record.role_name.as_deref(), Some("admin"));
+    assert_eq!(store.login_details("alice").await.unwrap().unwrap().password_hash, "hash");
+}
+
+async fn sqlite() -> SqliteStore {
+    // Every connection to `:memory:` gets its own database, so keep to one.
+    let database = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let store = SqliteStore::new(database);
+    store.migrate().await.unwrap();
+    store
+}
+
+#[tokio::test]
+async fn memory_store() {
+    check_store(&MemoryStore::new()).await;
+    check_bootstrap(&MemoryStore::new()).await;
+    check_bootstrap_creates(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn sqlite_store() {
+    check_store(&sqlite().await).await;
+    check_bootstrap(&sqlite().await).await;
+    check_bootstrap_creates(&sqlite().await).await;
+}
+
+#[sqlx::test(migrator = "MIGRATOR")]
+async fn postgres_store(database: PgPool) {
+    check_store(&PostgresStore::new(database)).await;
+}
+
+#[sqlx::test(migrator = "MIGRATOR")]
+async fn postgres_bootstrap(database: PgPool) {
+    check_bootstrap(&PostgresStore::new(database)).await;
+}
+
+#[sqlx::test(migrator = "MIGRATOR")]
+async fn postgres_bootstrap_creates(database: PgPool) {
+    check_bootstrap_creates(&PostgresStore::new(database)).await;
+}
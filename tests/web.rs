@@ -0,0 +1,1219 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use argon2::Params;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use cookie::{Cookie, SameSite};
+use hecksmosis::{
+    admin, get_router_with_store,
+    repository::{MemoryStore, PostgresStore, Repository, SqliteStore, Store},
+    shuttle_service, Config, CookieConfig, Mail, MailTransportConfig, MemoryMailbox, MIGRATOR,
+};
+use pbkdf2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+    Pbkdf2,
+};
+use sqlx::{
+    sqlite::{SqlitePool, SqlitePoolOptions},
+    PgPool,
+};
+use shuttle_runtime::Service;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tower::ServiceExt;
+
+const PASSWORD: &str = "correct horse";
+
+/// A response, with the body read into a string.
+struct Page {
+    status: StatusCode,
+    location: Option<String>,
+    content_type: Option<String>,
+    body: String,
+}
+
+impl Page {
+    fn assert_redirect(&self, location: &str) {
+        assert_eq!(self.status, StatusCode::SEE_OTHER, "{}", self.body);
+        assert_eq!(self.location.as_deref(), Some(location));
+    }
+}
+
+/// Talks to the site like a browser would: it keeps the cookies it is sent and
+/// sends back the CSRF token from the last page that had one.
+struct Client {
+    router: Router,
+    cookies: HashMap<String, String>,
+    csrf_token: Option<String>,
+}
+
+/// The site's configuration for tests, which keeps mail in memory.
+fn test_config() -> Config {
+    Config {
+        public_url: "http://localhost:8000".to_owned(),
+        mail_from: "Hecksmosis <noreply@example.com>".to_owned(),
+        mail_transport: MailTransportConfig::Memory(MemoryMailbox::default()),
+        // The default cost makes every signup and login take a noticeable time.
+        argon2_params: Params::new(Params::MIN_M_COST, 1, 1, None).unwrap(),
+        cookies: CookieConfig {
+            secure: false,
+            same_site: SameSite::Lax,
+        },
+        trusted_proxies: Vec::new(),
+        jwt_secret: b"test secret".to_vec(),
+        bootstrap_admin: None,
+    }
+}
+
+impl Client {
+    fn new(store: Store) -> Self {
+        Self::with_config(store, test_config())
+    }
+
+    fn with_config(store: Store, config: Config) -> Self {
+        Self {
+            router: get_router_with_store(store, config),
+            cookies: HashMap::new(),
+            csrf_token: None,
+        }
+    }
+
+    async fn send(&mut self, request: Request<Body>) -> Page {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+
+        for value in response.headers().get_all(header::SET_COOKIE) {
+            let cookie = Cookie::parse(value.to_str().unwrap().to_owned()).unwrap();
+            if cookie.max_age().is_some_and(|max_age| max_age.is_zero()) {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies.insert(cookie.name().to_owned(), cookie.value().to_owned());
+            }
+        }
+
+        let status = response.status();
+        let header = |name: header::HeaderName| {
+            let value = response.headers().get(name)?;
+            Some(value.to_str().unwrap().to_owned())
+        };
+        let location = header(header::LOCATION);
+        let content_type = header(header::CONTENT_TYPE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        if let Some(csrf_token) = csrf_token(&body) {
+            self.csrf_token = Some(csrf_token);
+        }
+
+        Page {
+            status,
+            location,
+            content_type,
+            body,
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> axum::http::request::Builder {
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::COOKIE, cookies.join("; "))
+    }
+
+    async fn get(&mut self, path: &str) -> Page {
+        let request = self.request("GET", path).body(Body::empty()).unwrap();
+        self.send(request).await
+    }
+
+    async fn post(&mut self, path: &str, form: &[(&str, &str)]) -> Page {
+        let mut body = form_urlencoded::Serializer::new(String::new());
+        body.extend_pairs(form);
+        if let Some(csrf_token) = &self.csrf_token {
+            body.append_pair("csrf_token", csrf_token);
+        }
+
+        let request = self
+            .request("POST", path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body.finish()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    fn logged_in(&self) -> bool {
+        self.cookies.contains_key("session")
+    }
+
+    async fn signup(&mut self, username: &str) -> Page {
+        self.post(
+            "/signup",
+            &[
+                ("username", username),
+                ("password", PASSWORD),
+                ("confirm_password", PASSWORD),
+            ],
+        )
+        .await
+    }
+
+    async fn login(&mut self, username: &str, password: &str) -> Page {
+        self.post("/login", &[("username", username), ("password", password)])
+            .await
+    }
+}
+
+fn csrf_token(body: &str) -> Option<String> {
+    let (_, rest) = body.split_once("name=\"csrf_token\" value=\"")?;
+    rest.split('"').next().map(String::from)
+}
+
+/// A fresh site on an SQLite database in memory, which is what the tests run
+/// on unless they say otherwise.
+struct Site {
+    /// For the few checks that need to reach into the database.
+    database: SqlitePool,
+    store: Store,
+}
+
+impl Site {
+    async fn new() -> Self {
+        // Every connection to `:memory:` gets its own database, so keep to one.
+        let database = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let store = SqliteStore::new(database.clone());
+        store.migrate().await.unwrap();
+
+        Self {
+            database,
+            store: Arc::new(store),
+        }
+    }
+
+    fn client(&self) -> Client {
+        Client::new(self.store.clone())
+    }
+
+    fn client_with_config(&self, config: Config) -> Client {
+        Client::with_config(self.store.clone(), config)
+    }
+
+    async fn user_id(&self, username: &str) -> i32 {
+        self.store.user_id(username).await.unwrap().unwrap()
+    }
+}
+
+/// Signs up in a fresh client and loads a page, so it has a CSRF token.
+async fn signed_up(site: &Site, username: &str) -> Client {
+    let mut client = site.client();
+    client.signup(username).await.assert_redirect("/");
+    client.get("/").await;
+    client
+}
+
+/// Signing up logs the new user in and gives them a profile page.
+#[tokio::test]
+async fn signup_logs_in() {
+    let site = Site::new().await;
+    let mut client = site.client();
+
+    let page = client.get("/signup").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("<form action=\"/signup\""));
+
+    client.signup("alice").await.assert_redirect("/");
+    assert!(client.logged_in());
+
+    let page = client.get("/").await;
+    assert!(page.body.contains("Logout"));
+    assert!(page.body.contains("<p class=\"notice success\">Welcome, alice!"));
+    assert!(page.body.contains("<p class=\"notice warning\">Add an email address"));
+    client.get("/me").await.assert_redirect("/user/alice");
+
+    let page = client.get("/user/alice").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("Role: user"));
+}
+
+/// Taken or invalid usernames and mismatched passwords are turned away.
+#[tokio::test]
+async fn signup_rejects_bad_forms() {
+    let site = Site::new().await;
+    signed_up(&site, "alice").await;
+    let mut client = site.client();
+
+    let page = client.signup("alice").await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("<p class=\"field-error\">Username already exists</p>"));
+    assert!(page.body.contains("value=\"alice\""));
+
+    let page = client.signup("Not Valid").await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Usernames can only use lowercase letters"));
+    assert!(page.body.contains("value=\"Not Valid\""));
+
+    let page = client
+        .post(
+            "/signup",
+            &[
+                ("username", "bob"),
+                ("password", PASSWORD),
+                ("confirm_password", "something else"),
+            ],
+        )
+        .await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Passwords do not match"));
+    assert!(page.body.contains("value=\"bob\""));
+    // Passwords are never sent back.
+    assert!(!page.body.contains(PASSWORD));
+    assert!(!client.logged_in());
+}
+
+/// Logging out drops the session cookie and logging back in needs the right password.
+#[tokio::test]
+async fn login_and_logout() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+
+    client.post("/logout", &[]).await.assert_redirect("/");
+    assert!(!client.logged_in());
+    let page = client.get("/").await;
+    assert!(page.body.contains("href=\"/login\""));
+    assert_eq!(client.get("/me").await.status, StatusCode::UNAUTHORIZED);
+
+    let page = client.login("alice", "wrong password").await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+    assert!(page.body.contains("Invalid username or password"));
+    assert!(page.body.contains("value=\"alice\""));
+    assert!(!client.logged_in());
+
+    client.login("alice", PASSWORD).await.assert_redirect("/");
+    assert!(client.logged_in());
+    client.get("/me").await.assert_redirect("/user/alice");
+}
+
+/// Another browser logged in to the same account.
+async fn logged_in_elsewhere(site: &Site, username: &str) -> Client {
+    let mut client = site.client();
+    client.login(username, PASSWORD).await.assert_redirect("/");
+    client.get("/").await;
+    client
+}
+
+/// Logging out everywhere ends the sessions of other browsers too.
+#[tokio::test]
+async fn logout_everywhere_ends_other_sessions() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let mut other = logged_in_elsewhere(&site, "alice").await;
+    other.get("/me").await.assert_redirect("/user/alice");
+
+    alice.post("/logout/all", &[]).await.assert_redirect("/");
+    assert!(!alice.logged_in());
+    assert!(other.logged_in());
+    assert_eq!(other.get("/me").await.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Changing the password ends every other session, but keeps the one it was
+/// changed from.
+#[tokio::test]
+async fn password_change_ends_other_sessions() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let mut other = logged_in_elsewhere(&site, "alice").await;
+
+    let new_password = "battery staple";
+    let form = [
+        ("current_password", PASSWORD),
+        ("password", new_password),
+        ("confirm_password", new_password),
+    ];
+    alice.post("/me/password", &form).await.assert_redirect("/me");
+
+    alice.get("/me").await.assert_redirect("/user/alice");
+    assert!(other.logged_in());
+    assert_eq!(other.get("/me").await.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Forms posted by a logged in user without the CSRF token are refused.
+#[tokio::test]
+async fn logged_in_forms_need_csrf_token() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    client.csrf_token = None;
+
+    let page = client.post("/profile", &[("profile", "Hello")]).await;
+    assert_eq!(page.status, StatusCode::FORBIDDEN);
+}
+
+/// A profile edit shows up for everyone, but only its owner gets the form.
+#[tokio::test]
+async fn edit_profile() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+
+    let page = alice.post("/profile", &[("profile", "Hello from Alice")]).await;
+    page.assert_redirect("/me");
+
+    // The notice waits through the redirect and is shown once.
+    alice.get("/me").await.assert_redirect("/user/alice");
+    let page = alice.get("/user/alice").await;
+    assert!(page.body.contains("Hello from Alice"));
+    assert!(page.body.contains("Your profile has been saved"));
+    let page = alice.get("/user/alice").await;
+    assert!(!page.body.contains("Your profile has been saved"));
+
+    // Profiles that are too long are shown again with what was typed.
+    let long_profile = "a".repeat(2001);
+    let page = alice.post("/profile", &[("profile", &long_profile)]).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Profiles can be at most 2000 characters long"));
+    assert!(page.body.contains(&long_profile));
+    assert!(alice.get("/user/alice").await.body.contains("Hello from Alice"));
+
+    let page = alice.post("/me/email", &[("email", "not an address")]).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Enter a valid email address"));
+    assert!(page.body.contains("value=\"not an address\""));
+    assert!(page.body.contains(">Hello from Alice</textarea>"));
+
+    // Others see the profile, but not the form to edit it.
+    let mut bob = signed_up(&site, "bob").await;
+    let page = bob.get("/user/alice").await;
+    assert!(page.body.contains("Hello from Alice"));
+    assert!(!page.body.contains("action=\"/profile\""));
+}
+
+/// Profiles are shown escaped to everyone who looks at them.
+#[tokio::test]
+async fn profile_is_escaped_for_visitors() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let page = alice.post("/profile", &[("profile", "<script>alert(1)</script>")]).await;
+    page.assert_redirect("/me");
+
+    let mut bob = signed_up(&site, "bob").await;
+    let page = bob.get("/user/alice").await;
+    assert!(page.body.contains("&lt;script&gt;alert(1)&lt;&#x2F;script&gt;"));
+    assert!(!page.body.contains("<script>"));
+}
+
+/// Whether the admin page lists `username` with `role`.
+fn lists_role(page: &Page, username: &str, role: &str) -> bool {
+    page.body.contains(&format!("{}</a></td>\n            <td>{}</td>", username, role))
+}
+
+/// Admins can change other users' roles from the admin page.
+#[tokio::test]
+async fn admin_promotes_and_demotes() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let mut bob = signed_up(&site, "bob").await;
+    admin::promote(&site.store, "alice").await.unwrap();
+
+    assert_eq!(bob.get("/admin").await.status, StatusCode::UNAUTHORIZED);
+
+    let page = alice.get("/admin").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(lists_role(&page, "bob", "user"));
+    assert!(page.body.contains("<form method=\"post\" action=\"/admin/add/bob\">"));
+
+    let page = alice.post("/admin/add/bob", &[("confirm", "on")]).await;
+    page.assert_redirect("/admin");
+    let page = alice.get("/admin").await;
+    assert!(page.body.contains("bob is now an admin"));
+    assert!(lists_role(&page, "bob", "admin"));
+    assert_eq!(bob.get("/admin").await.status, StatusCode::OK);
+
+    // The notice is only shown once.
+    let page = alice.get("/admin").await;
+    assert!(!page.body.contains("bob is now an admin"));
+
+    let page = alice.post("/admin/remove/bob", &[("confirm", "on")]).await;
+    page.assert_redirect("/admin");
+    let page = alice.get("/admin").await;
+    assert!(page.body.contains("bob is no longer an admin"));
+    assert!(lists_role(&page, "bob", "user"));
+    assert_eq!(bob.get("/admin").await.status, StatusCode::UNAUTHORIZED);
+
+    // Users can't change roles themselves.
+    let page = bob.post("/admin/add/bob", &[("confirm", "on")]).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Role changes that aren't confirmed or can't be made are reported back on
+/// the admin page.
+#[tokio::test]
+async fn admin_reports_failed_role_changes() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    signed_up(&site, "bob").await;
+    admin::promote(&site.store, "alice").await.unwrap();
+    alice.get("/admin").await;
+
+    alice.post("/admin/add/bob", &[]).await.assert_redirect("/admin");
+    let page = alice.get("/admin").await;
+    assert!(page.body.contains("Confirm the role change"));
+    assert!(lists_role(&page, "bob", "user"));
+
+    let page = alice.post("/admin/remove/bob", &[("confirm", "on")]).await;
+    page.assert_redirect("/admin");
+    assert!(alice.get("/admin").await.body.contains("bob isn&#x27;t an admin"));
+
+    // The last admin can't demote themselves.
+    let page = alice.post("/admin/remove/alice", &[("confirm", "on")]).await;
+    page.assert_redirect("/admin");
+    let page = alice.get("/admin").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("There must always be at least one admin"));
+    assert!(lists_role(&page, "alice", "admin"));
+}
+
+/// Deleting an account logs out and removes the user for good.
+#[tokio::test]
+async fn delete_account() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+
+    client.post("/delete", &[]).await.assert_redirect("/");
+    assert!(!client.logged_in());
+
+    assert_eq!(client.get("/user/alice").await.status, StatusCode::NOT_FOUND);
+    let page = client.login("alice", PASSWORD).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Guessing the current password when changing it runs into the login
+/// lockout, so a stolen session can't be used to find out the password.
+#[tokio::test]
+async fn password_change_guesses_are_throttled() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    let new_password = "battery staple";
+
+    for _ in 0..5 {
+        let form = [
+            ("current_password", "wrong password"),
+            ("password", new_password),
+            ("confirm_password", new_password),
+        ];
+        let page = client.post("/me/password", &form).await;
+        assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+    }
+
+    let form = [
+        ("current_password", PASSWORD),
+        ("password", new_password),
+        ("confirm_password", new_password),
+    ];
+    let page = client.post("/me/password", &form).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+    let page = site.client().login("alice", PASSWORD).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// What browsers send about themselves is shown escaped on the sessions page.
+#[tokio::test]
+async fn sessions_page_escapes_user_agent() {
+    let site = Site::new().await;
+    let mut client = site.client();
+    let body = "username=alice&password=correct+horse&confirm_password=correct+horse";
+    let request = client
+        .request("POST", "/signup")
+        .header(header::USER_AGENT, "<script>alert(1)</script>")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))
+        .unwrap();
+    client.send(request).await.assert_redirect("/");
+
+    let page = client.get("/me/sessions").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(!page.body.contains("<script>"));
+    assert!(page.body.contains("&lt;script&gt;alert(1)&lt;&#x2F;script&gt;"));
+}
+
+/// New passwords are checked like on signup, and a rejected one shows the form
+/// again with what was wrong.
+#[tokio::test]
+async fn short_new_passwords_are_rejected() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    alice.post("/me/email", &[("email", "alice@example.com")]).await;
+
+    let page = alice.get("/me/password").await;
+    assert!(page.body.contains("minlength=\"8\""));
+    let form = [
+        ("current_password", PASSWORD),
+        ("password", "short"),
+        ("confirm_password", "short"),
+    ];
+    let page = alice.post("/me/password", &form).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Passwords need at least 8 characters"));
+    assert!(page.body.contains("action=\"/me/password\""));
+
+    let mailbox = MemoryMailbox::default();
+    let mut config = test_config();
+    config.mail_transport = MailTransportConfig::Memory(mailbox.clone());
+    let mut client = site.client_with_config(config);
+    client.post("/forgot-password", &[("email", "alice@example.com")]).await;
+    let token = reset_token(&wait_for_mail(&mailbox, 1).await[0].body);
+
+    let page = client.get(&format!("/reset-password?token={}", token)).await;
+    assert!(page.body.contains("minlength=\"8\""));
+    let form = [
+        ("token", token.as_str()),
+        ("password", "short"),
+        ("confirm_password", "short"),
+    ];
+    let page = client.post("/reset-password", &form).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("Passwords need at least 8 characters"));
+    assert!(page.body.contains(&format!("value=\"{}\"", token)));
+
+    // The link still works for a better password.
+    let form = [
+        ("token", token.as_str()),
+        ("password", "battery staple"),
+        ("confirm_password", "battery staple"),
+    ];
+    client.post("/reset-password", &form).await.assert_redirect("/login");
+}
+
+/// The link in a password reset mail works once, and not after it expires.
+#[tokio::test]
+async fn password_reset_by_mail() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let page = alice.post("/me/email", &[("email", "alice@example.com")]).await;
+    page.assert_redirect("/me");
+
+    let mailbox = MemoryMailbox::default();
+    let mut config = test_config();
+    config.mail_transport = MailTransportConfig::Memory(mailbox.clone());
+    let mut client = site.client_with_config(config);
+    client.get("/forgot-password").await;
+
+    // Unknown addresses get the same answer, but no mail.
+    let page = client.post("/forgot-password", &[("email", "bob@example.com")]).await;
+    page.assert_redirect("/login");
+
+    let page = client.post("/forgot-password", &[("email", "alice@example.com")]).await;
+    page.assert_redirect("/login");
+    let messages = wait_for_mail(&mailbox, 1).await;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].to, "alice@example.com");
+    let token = reset_token(&messages[0].body);
+
+    let page = client.get(&format!("/reset-password?token={}", token)).await;
+    assert_eq!(page.status, StatusCode::OK);
+    let new_password = "battery staple";
+    let form = [
+        ("token", token.as_str()),
+        ("password", new_password),
+        ("confirm_password", new_password),
+    ];
+    client.post("/reset-password", &form).await.assert_redirect("/login");
+
+    // The reset logged alice out everywhere.
+    assert!(!alice.get("/").await.body.contains("Logout"));
+    assert_eq!(client.login("alice", PASSWORD).await.status, StatusCode::UNAUTHORIZED);
+    client.login("alice", new_password).await.assert_redirect("/");
+    client.post("/logout", &[]).await;
+    client.get("/forgot-password").await;
+
+    // Each link can only be used once.
+    let page = client.get(&format!("/reset-password?token={}", token)).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    let page = client.post("/reset-password", &form).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+
+    client.post("/forgot-password", &[("email", "alice@example.com")]).await;
+    let token = reset_token(&wait_for_mail(&mailbox, 2).await[1].body);
+    sqlx::query("UPDATE password_reset_tokens SET expires_at = unixepoch() - 1;")
+        .execute(&site.database)
+        .await
+        .unwrap();
+    let page = client.get(&format!("/reset-password?token={}", token)).await;
+    assert_eq!(page.status, StatusCode::BAD_REQUEST);
+    assert!(page.body.contains("invalid or has expired"));
+}
+
+/// The mail sent so far, once there are at least `count`. Reset mails are sent
+/// after the response, so they may not have arrived yet.
+async fn wait_for_mail(mailbox: &MemoryMailbox, count: usize) -> Vec<Mail> {
+    for _ in 0..200 {
+        let messages = mailbox.messages();
+        if messages.len() >= count {
+            return messages;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("expected {} mails, got {}", count, mailbox.messages().len());
+}
+
+/// A password reset request for `email`, as if made from `peer`.
+fn forgot_password(client: &Client, peer: &str, email: &str) -> Request<Body> {
+    let peer: SocketAddr = peer.parse().unwrap();
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("email", email)
+        .finish();
+    let mut request = client
+        .request("POST", "/forgot-password")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))
+        .unwrap();
+    request.extensions_mut().insert(ConnectInfo(peer));
+    request
+}
+
+/// Addresses are saved and looked up trimmed and in lowercase.
+#[tokio::test]
+async fn password_reset_email_is_normalized() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let page = alice.post("/me/email", &[("email", " Alice@Example.com ")]).await;
+    page.assert_redirect("/me");
+    let user = site.store.user_by_email("alice@example.com").await.unwrap();
+    assert_eq!(user.unwrap().email.as_deref(), Some("alice@example.com"));
+
+    let mailbox = MemoryMailbox::default();
+    let mut config = test_config();
+    config.mail_transport = MailTransportConfig::Memory(mailbox.clone());
+    let mut client = site.client_with_config(config);
+    let page = client.post("/forgot-password", &[("email", "  ALICE@example.COM\t")]).await;
+    page.assert_redirect("/login");
+    assert_eq!(wait_for_mail(&mailbox, 1).await[0].to, "alice@example.com");
+}
+
+/// Reset requests are limited for each email and each address they come from,
+/// whether or not the email belongs to anyone.
+#[tokio::test]
+async fn password_reset_requests_are_limited() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    alice.post("/me/email", &[("email", "alice@example.com")]).await;
+
+    let mailbox = MemoryMailbox::default();
+    let mut config = test_config();
+    config.mail_transport = MailTransportConfig::Memory(mailbox.clone());
+    let mut client = site.client_with_config(config);
+
+    for attempt in 0..4 {
+        let request = forgot_password(&client, "192.0.2.1:1234", "alice@example.com");
+        let expected = if attempt < 3 {
+            StatusCode::SEE_OTHER
+        } else {
+            StatusCode::TOO_MANY_REQUESTS
+        };
+        assert_eq!(client.send(request).await.status, expected);
+    }
+    assert_eq!(wait_for_mail(&mailbox, 3).await.len(), 3);
+
+    // The three requests above count towards the address's ten.
+    for attempt in 0..8 {
+        let email = format!("nobody{}@example.com", attempt);
+        let request = forgot_password(&client, "192.0.2.1:1234", &email);
+        let expected = if attempt < 7 {
+            StatusCode::SEE_OTHER
+        } else {
+            StatusCode::TOO_MANY_REQUESTS
+        };
+        assert_eq!(client.send(request).await.status, expected);
+    }
+    let request = forgot_password(&client, "192.0.2.2:1234", "nobody@example.com");
+    assert_eq!(client.send(request).await.status, StatusCode::SEE_OTHER);
+}
+
+/// The token from the link in a password reset mail.
+fn reset_token(body: &str) -> String {
+    let (_, rest) = body.split_once("/reset-password?token=").unwrap();
+    rest.split_whitespace().next().unwrap().to_owned()
+}
+
+/// A code from `totp` for `steps` time steps from now.
+fn totp_code(totp: &TOTP, steps: i64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    totp.generate((now + steps * totp.step as i64) as u64)
+}
+
+/// Turns on two-factor authentication from the settings page, returning the
+/// authenticator and the recovery codes that were shown.
+async fn enable_two_factor(client: &mut Client) -> (TOTP, Vec<String>) {
+    client.post("/me/2fa/setup", &[]).await.assert_redirect("/me/2fa");
+    let page = client.get("/me/2fa").await;
+    let (_, rest) = page.body.split_once("Key: <code>").unwrap();
+    let secret = rest.split('<').next().unwrap().to_owned();
+    let secret = Secret::Encoded(secret).to_bytes().unwrap();
+    let totp = TOTP::new(Algorithm::SHA1, 6, 0, 30, secret, None, "alice".to_owned()).unwrap();
+
+    let page = client.post("/me/2fa/enable", &[("code", &totp_code(&totp, 0))]).await;
+    assert_eq!(page.status, StatusCode::OK);
+    let recovery_codes = page
+        .body
+        .split("<li><code>")
+        .skip(1)
+        .map(|rest| rest.split('<').next().unwrap().to_owned())
+        .collect();
+    (totp, recovery_codes)
+}
+
+/// Looking at the two-factor page changes nothing, a secret is only made when
+/// asked for and turning it on needs a code for that secret.
+#[tokio::test]
+async fn two_factor_enrolment() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+
+    let page = client.get("/me/2fa").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("action=\"/me/2fa/setup\""));
+    let secrets = site.store.totp_secrets(site.user_id("alice").await).await.unwrap();
+    assert!(secrets.unwrap().pending_secret.is_none());
+
+    client.post("/me/2fa/setup", &[]).await.assert_redirect("/me/2fa");
+    let page = client.get("/me/2fa").await;
+    assert!(page.body.contains("Key: <code>"));
+    let page = client.post("/me/2fa/enable", &[("code", "000000")]).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+
+    let (_, recovery_codes) = enable_two_factor(&mut client).await;
+    assert_eq!(recovery_codes.len(), 10);
+    let page = client.get("/me/2fa").await;
+    assert!(page.body.contains("You have 10 recovery codes left"));
+}
+
+/// Logging in with two-factor authentication on needs a code or an unused
+/// recovery code after the password.
+#[tokio::test]
+async fn two_factor_login() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    let (totp, recovery_codes) = enable_two_factor(&mut client).await;
+    client.get("/").await;
+    client.post("/logout", &[]).await;
+
+    client.login("alice", PASSWORD).await.assert_redirect("/login/2fa");
+    assert!(!client.logged_in());
+    client.get("/login/2fa").await;
+    let page = client.post("/login/2fa", &[("code", "000000")]).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+    let page = client.post("/login/2fa", &[("code", &totp_code(&totp, 1))]).await;
+    page.assert_redirect("/");
+    assert!(client.logged_in());
+    client.get("/").await;
+    client.post("/logout", &[]).await;
+
+    for expected in [StatusCode::SEE_OTHER, StatusCode::UNAUTHORIZED] {
+        client.login("alice", PASSWORD).await.assert_redirect("/login/2fa");
+        client.get("/login/2fa").await;
+        let page = client.post("/login/2fa", &[("code", &recovery_codes[0])]).await;
+        assert_eq!(page.status, expected);
+        client.get("/").await;
+        client.post("/logout", &[]).await;
+    }
+
+    // Recovery codes are accepted however they are typed.
+    client.login("alice", PASSWORD).await.assert_redirect("/login/2fa");
+    client.get("/login/2fa").await;
+    let code = recovery_codes[1].replace('-', " ").to_uppercase();
+    client.post("/login/2fa", &[("code", &code)]).await.assert_redirect("/");
+    assert!(client.get("/me/2fa").await.body.contains("You have 8 recovery codes left"));
+}
+
+/// Wrong codes count as failed logins, so logging in again for a new challenge
+/// doesn't give more guesses.
+#[tokio::test]
+async fn two_factor_failures_lock_login() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    let (totp, _) = enable_two_factor(&mut client).await;
+    client.get("/").await;
+    client.post("/logout", &[]).await;
+
+    // Five failures lock the username, spread over two challenges.
+    for attempts in [3, 2] {
+        client.login("alice", PASSWORD).await.assert_redirect("/login/2fa");
+        client.get("/login/2fa").await;
+        for _ in 0..attempts {
+            let page = client.post("/login/2fa", &[("code", "000000")]).await;
+            assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    // Not even the right code gets in now.
+    let page = client.post("/login/2fa", &[("code", &totp_code(&totp, 1))]).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(!client.logged_in());
+    let page = client.login("alice", PASSWORD).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// Guessing the password to turn two-factor authentication off counts against
+/// the same limit as logins.
+#[tokio::test]
+async fn two_factor_disable_guesses_are_throttled() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    enable_two_factor(&mut client).await;
+
+    for _ in 0..5 {
+        let page = client.post("/me/2fa/disable", &[("password", "wrong password")]).await;
+        assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+    }
+
+    let page = client.post("/me/2fa/disable", &[("password", PASSWORD)]).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+    let secrets = site.store.totp_secrets(site.user_id("alice").await).await.unwrap();
+    assert!(secrets.unwrap().secret.is_some());
+}
+
+/// A login with the wrong password, as if made from `peer`.
+fn failed_login(client: &Client, peer: &str, username: &str, forwarded_for: &str) -> Request<Body> {
+    let peer: SocketAddr = peer.parse().unwrap();
+    let mut request = client
+        .request("POST", "/login")
+        .header("X-Forwarded-For", forwarded_for)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(format!("username={}&password=wrong", username)))
+        .unwrap();
+    request.extensions_mut().insert(ConnectInfo(peer));
+    request
+}
+
+/// Failed logins are counted against the address the request really came from,
+/// whatever the client claims in `X-Forwarded-For`.
+#[tokio::test]
+async fn forged_forwarded_for_keeps_address_throttled() {
+    let site = Site::new().await;
+    let mut config = test_config();
+    config.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    let mut client = site.client_with_config(config);
+
+    // The client makes up the first entry, the proxy appends the real one.
+    for attempt in 0..=20 {
+        let username = format!("user{}", attempt);
+        let forwarded_for = format!("198.51.100.{}, 203.0.113.7", attempt);
+        let request = failed_login(&client, "10.0.0.1:443", &username, &forwarded_for);
+        let expected = if attempt < 20 {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::TOO_MANY_REQUESTS
+        };
+        assert_eq!(client.send(request).await.status, expected);
+    }
+
+    // Without going through the proxy the header isn't believed at all.
+    let request = failed_login(&client, "203.0.113.7:1234", "someone", "10.0.0.1");
+    assert_eq!(client.send(request).await.status, StatusCode::TOO_MANY_REQUESTS);
+    let request = failed_login(&client, "192.0.2.1:1234", "someone", "203.0.113.7");
+    assert_eq!(client.send(request).await.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Without the connection's address, the last entry a trusted proxy added to
+/// `X-Forwarded-For` is still counted.
+#[tokio::test]
+async fn forwarded_for_is_used_without_peer_address() {
+    let site = Site::new().await;
+    let mut config = test_config();
+    config.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    let mut client = site.client_with_config(config);
+
+    for attempt in 0..=20 {
+        let request = client
+            .request("POST", "/login")
+            .header("X-Forwarded-For", format!("198.51.100.{}, 203.0.113.7", attempt))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("username=user{}&password=wrong", attempt)))
+            .unwrap();
+        let expected = if attempt < 20 {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::TOO_MANY_REQUESTS
+        };
+        assert_eq!(client.send(request).await.status, expected);
+    }
+}
+
+/// Serves the site on a real socket, set up the same way the `hecksmosis`
+/// binary sets it up on Shuttle, and returns where it listens.
+async fn serve_like_shuttle(database: PgPool, config: Config) -> SocketAddr {
+    let service = shuttle_service(database, config).await.unwrap();
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::spawn(service.bind(address));
+
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    address
+}
+
+/// The deployed site knows where connections come from, so one address
+/// guessing at many accounts gets locked out.
+#[sqlx::test(migrations = false)]
+async fn shuttle_service_throttles_addresses(database: PgPool) {
+    let address = serve_like_shuttle(database, test_config()).await;
+    let client = hyper::Client::new();
+
+    for attempt in 0..=20 {
+        let request = Request::post(format!("http://{}/login", address))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("username=user{}&password=wrong", attempt)))
+            .unwrap();
+        let expected = if attempt < 20 {
+            StatusCode::UNAUTHORIZED
+        } else {
+            StatusCode::TOO_MANY_REQUESTS
+        };
+        assert_eq!(client.request(request).await.unwrap().status(), expected);
+    }
+}
+
+/// Passwords hashed with PBKDF2 before the switch to Argon2id still work, and
+/// are hashed again with Argon2id when their user logs in.
+#[tokio::test]
+async fn legacy_password_is_rehashed() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    client.post("/logout", &[]).await;
+
+    // Few rounds, since the default takes seconds in a debug build.
+    let params = pbkdf2::Params {
+        rounds: 1000,
+        output_length: 32,
+    };
+    let salt = SaltString::generate(&mut OsRng);
+    let legacy_hash = Pbkdf2
+        .hash_password_customized(PASSWORD.as_bytes(), None, None, params, &salt)
+        .unwrap()
+        .to_string();
+    let alice = site.user_id("alice").await;
+    site.store.set_password_hash(alice, &legacy_hash).await.unwrap();
+
+    client.login("alice", PASSWORD).await.assert_redirect("/");
+
+    let hash = site.store.password_hash(alice).await.unwrap().unwrap();
+    assert!(hash.starts_with("$argon2id$"), "{}", hash);
+
+    client.get("/").await;
+    client.post("/logout", &[]).await;
+    client.login("alice", PASSWORD).await.assert_redirect("/");
+}
+
+/// Asks the API for tokens with a password and two-factor code.
+async fn api_password_grant(client: &mut Client, code: &str) -> Page {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs([
+            ("grant_type", "password"),
+            ("username", "alice"),
+            ("password", PASSWORD),
+            ("code", code),
+        ])
+        .finish();
+    let request = client
+        .request("POST", "/api/token")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))
+        .unwrap();
+    client.send(request).await
+}
+
+/// Guessing two-factor codes through the API runs into the same lockout as
+/// guessing passwords, even though each request sends the right password.
+#[tokio::test]
+async fn api_two_factor_guesses_are_throttled() {
+    let site = Site::new().await;
+    let mut client = signed_up(&site, "alice").await;
+    let (totp, _) = enable_two_factor(&mut client).await;
+    let mut api = site.client();
+
+    let page = api_password_grant(&mut api, &totp_code(&totp, 1)).await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("access_token"));
+
+    for _ in 0..5 {
+        let page = api_password_grant(&mut api, "000000").await;
+        assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+    }
+
+    let page = api_password_grant(&mut api, &totp_code(&totp, -1)).await;
+    assert_eq!(page.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(!page.body.contains("access_token"));
+
+    let (challenges,): (i64,) = sqlx::query_as("SELECT count(*) FROM login_challenges;")
+        .fetch_one(&site.database)
+        .await
+        .unwrap();
+    assert_eq!(challenges, 0);
+}
+
+/// Sends a GET request authenticated with an API token instead of cookies.
+async fn get_with_token(client: &mut Client, path: &str, token: &str) -> Page {
+    let request = Request::builder()
+        .uri(path)
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    client.send(request).await
+}
+
+/// Creates a personal access token from the tokens page, returning it and the
+/// page it was shown on.
+async fn create_token(client: &mut Client, form: &[(&str, &str)]) -> (String, Page) {
+    client.get("/me/tokens").await;
+    let page = client.post("/me/tokens", form).await;
+    assert_eq!(page.status, StatusCode::OK);
+    let (_, rest) = page.body.split_once("<p><code>").unwrap();
+    let token = rest.split('<').next().unwrap().to_owned();
+    (token, page)
+}
+
+/// Token names are shown escaped, and only admins can give a token the
+/// `admin` scope.
+#[tokio::test]
+async fn tokens_only_get_allowed_scopes() {
+    let site = Site::new().await;
+    let mut bob = signed_up(&site, "bob").await;
+    let form = [
+        ("name", "<b>script</b>"),
+        ("profile_read", "on"),
+        ("admin", "on"),
+    ];
+    let (token, page) = create_token(&mut bob, &form).await;
+    assert!(page.body.contains("&lt;b&gt;script&lt;&#x2F;b&gt;"));
+    assert!(page.body.contains("Scopes: <code>profile:read</code>"));
+    assert!(!page.body.contains("<code>admin</code>"));
+
+    get_with_token(&mut bob, "/me", &token).await.assert_redirect("/user/bob");
+
+    // Not even once bob is an admin does the token reach the admin page.
+    admin::promote(&site.store, "bob").await.unwrap();
+    assert_eq!(bob.get("/admin").await.status, StatusCode::OK);
+    let page = get_with_token(&mut bob, "/admin", &token).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+}
+
+/// A revoked token stops working straight away.
+#[tokio::test]
+async fn revoked_token_is_rejected() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+    let form = [("name", "script"), ("profile_read", "on")];
+    let (token, page) = create_token(&mut alice, &form).await;
+    get_with_token(&mut alice, "/me", &token).await.assert_redirect("/user/alice");
+
+    let (_, rest) = page.body.split_once("action=\"/me/tokens/").unwrap();
+    let id = rest.split('/').next().unwrap();
+    let page = alice.post(&format!("/me/tokens/{}/revoke", id), &[]).await;
+    page.assert_redirect("/me/tokens");
+
+    let page = get_with_token(&mut alice, "/me", &token).await;
+    assert_eq!(page.status, StatusCode::UNAUTHORIZED);
+}
+
+/// Clients asking for JSON get errors as problem documents with the error's
+/// status, while browsers still get the error page.
+#[tokio::test]
+async fn errors_are_negotiated() {
+    let site = Site::new().await;
+    let mut alice = signed_up(&site, "alice").await;
+
+    let request = alice
+        .request("GET", "/user/nobody")
+        .header(header::ACCEPT, "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let page = alice.send(request).await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.content_type.as_deref(), Some("application/problem+json"));
+    let problem: serde_json::Value = serde_json::from_str(&page.body).unwrap();
+    assert_eq!(problem["status"], 404);
+    assert_eq!(problem["title"], "Not Found");
+    assert!(problem["detail"].as_str().is_some_and(|detail| !detail.is_empty()));
+
+    // A token that may only read can't change the profile.
+    let (token, _) = create_token(&mut alice, &[("name", "script"), ("profile_read", "on")]).await;
+    let request = Request::builder()
+        .method("POST")
+        .uri("/profile")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(header::ACCEPT, "application/problem+json")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from("profile=Hello"))
+        .unwrap();
+    let page = alice.send(request).await;
+    assert_eq!(page.status, StatusCode::FORBIDDEN);
+    assert_eq!(page.content_type.as_deref(), Some("application/problem+json"));
+    let problem: serde_json::Value = serde_json::from_str(&page.body).unwrap();
+    assert_eq!(problem["status"], 403);
+    assert_eq!(problem["detail"], "Token is missing the 'profile:write' scope");
+
+    let request = alice
+        .request("GET", "/user/nobody")
+        .header(header::ACCEPT, "text/html,application/xhtml+xml")
+        .body(Body::empty())
+        .unwrap();
+    let page = alice.send(request).await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.content_type.as_deref(), Some("text/html; charset=utf-8"));
+    assert!(page.body.contains("<h1>404 Not Found</h1>"));
+}
+
+/// Goes through two-factor login, the sessions page and access tokens, for
+/// checking that a store can run the whole site.
+async fn check_site(store: Store) {
+    let mut client = Client::new(store);
+    client.signup("alice").await.assert_redirect("/");
+    client.get("/").await;
+    let (totp, _) = enable_two_factor(&mut client).await;
+    client.get("/").await;
+    client.post("/logout", &[]).await;
+
+    client.login("alice", PASSWORD).await.assert_redirect("/login/2fa");
+    client.get("/login/2fa").await;
+    let page = client.post("/login/2fa", &[("code", &totp_code(&totp, 1))]).await;
+    page.assert_redirect("/");
+
+    let page = client.get("/me/sessions").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert!(page.body.contains("/revoke"));
+
+    let form = [("name", "script"), ("profile_read", "on")];
+    let (token, _) = create_token(&mut client, &form).await;
+    get_with_token(&mut client, "/me", &token).await.assert_redirect("/user/alice");
+}
+
+#[tokio::test]
+async fn site_runs_on_memory_store() {
+    check_site(Arc::new(MemoryStore::new())).await;
+}
+
+#[tokio::test]
+async fn site_runs_on_sqlite_store() {
+    check_site(Site::new().await.store).await;
+}
+
+#[sqlx::test(migrator = "MIGRATOR")]
+async fn site_runs_on_postgres_store(database: PgPool) {
+    check_site(Arc::new(PostgresStore::new(database))).await;
+}